@@ -1,25 +1,54 @@
-///  Program: lib.rs
-///  Author: Bill Meany
-///  Date: 04/27/2021
-///  Version: 1.0.0
-///  Revision date: 04/27/2021
-///  Revision: 1.0.0
-
-use log::info;
-use std::convert::TryInto;
+//  Program: lib.rs
+//  Author: Bill Meany
+//  Date: 04/27/2021
+//  Version: 1.0.0
+//  Revision date: 04/27/2021
+//  Revision: 1.0.0
+
+use log::{error, info, warn};
 use std::env;
 use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufRead, BufReader, IsTerminal, Write};
 use std::os::windows::prelude::*;
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf, Prefix};
 use std::process;
 
+//	Windows file system constants. Not all of them are used but they are
+//	all listed for documentation purposes.
+
+pub const FILE_ATTRIBUTE_READONLY: u32 =            0x00000001;
+pub const FILE_ATTRIBUTE_HIDDEN: u32 =              0x00000002;
+pub const FILE_ATTRIBUTE_SYSTEM: u32 =              0x00000004;
+pub const FILE_ATTRIBUTE_DIRECTORY: u32 =           0x00000010;
+pub const FILE_ATTRIBUTE_ARCHIVE: u32 =             0x00000020;
+pub const FILE_ATTRIBUTE_DEVICE: u32 =              0x00000040;
+pub const FILE_ATTRIBUTE_NORMAL: u32 =              0x00000080;
+pub const FILE_ATTRIBUTE_TEMPORARY: u32 =           0x00000100;
+pub const FILE_ATTRIBUTE_SPARSE_FILE: u32 =         0x00000200;
+pub const FILE_ATTRIBUTE_REPARSE_POINT: u32 =       0x00000400;
+pub const FILE_ATTRIBUTE_COMPRESSED: u32 =          0x00000800;
+pub const FILE_ATTRIBUTE_OFFLINE: u32 =             0x00001000;
+pub const FILE_ATTRIBUTE_NOT_CONTENT_INDEXED: u32 = 0x00002000;
+pub const FILE_ATTRIBUTE_ENCRYPTED: u32 =           0x00004000;
+pub const FILE_ATTRIBUTE_INTEGRITY_STREAM: u32 =    0x00008000;
+pub const FILE_ATTRIBUTE_VIRTUAL: u32 =             0x00010000;
+pub const FILE_ATTRIBUTE_NO_SCRUB_DATA: u32 =       0x00020000;
+pub const FILE_ATTRIBUTE_EA: u32 =                  0x00040000;
+pub const FILE_ATTRIBUTE_RECALL_ON_OPEN: u32 =      0x00040000;
+pub const FILE_ATTRIBUTE_PINNED: u32 =              0x00080000;
+pub const FILE_ATTRIBUTE_UNPINNED: u32 =            0x00100000;
+pub const FILE_ATTRIBUTE_RECALL_ON_DATA_ACCESS: u32 = 0x00400000;
+
 //	Simple housekeeping routine. Check to see if the correct number of para-
 //	meters were present on the command line. If so, isolate the program name
-//	and return it to the caller.
+//	and return it to the caller. Anything beyond the required parameters is
+//	an optional command (such as find) and is handled by the caller.
 //
 //	Function parameters:
 //
-//	nparm - number of command line arguments expected
+//	nparm - minimum number of command line arguments expected
 //	pgm_name - name of the program we were invoked under
 //
 
@@ -27,106 +56,508 @@ pub fn house_keeping(nparm: u16,pgm_name: &mut String) {
 
 	let cli_args: Vec<String> = env::args().collect();
 
-	if cli_args.len() != nparm.try_into().unwrap() {
+	if cli_args.len() < usize::from(nparm) {
 		println!("Incorrect number of parameters provided");
 		process::exit(0)
 	}
 	
-	let cli_arg01 = &cli_args[0];
-	let cli_arg01_tokens: Vec<&str>= cli_arg01.split("\\").collect();
-	let full_prog_name: Vec<&str> = cli_arg01_tokens[2].split(".").collect();
-	let just_program: String = full_prog_name[0].to_owned();
+	let cli_arg01 = Path::new(&cli_args[0]);
+	let just_program = match cli_arg01.file_stem() {
+		Some(stem) => stem.to_string_lossy().to_string(),
+		None => String::from("sfbprp"),
+	};
 	pgm_name.push_str(&just_program);
 	
 }
 
-//	Build a name for our log file.
+//	Build a name for our log file. The log location from the command line
+//	is joined with the log file pattern, in which {job} is replaced by the
+//	job name and {date} by the date and time. The directory is created if
+//	it does not exist. If a log file of that name already exists, because
+//	another run started in the same second, a -1, -2 ... suffix is added.
+//	The file is created here, only if it does not exist yet, so that two
+//	runs starting together cannot both settle on the same name.
 //	
 //	Function parameters:
 //
 //	lfn - mutable reference to callers variable
-//	lfp - reference to the log file prefix value
-//	jpn - reference to the current program name
+//	lfp - reference to the log file pattern
+//	jpn - reference to the current job (program) name
 //
-//	Example log file name might be:
+//	Example log file name with the default pattern {job}/Log_{date}.txt
+//	might be:
 //
 //	C:\Logs\my_program\Log_20200101-070707.txt
 //
 
-pub fn construct_lf_name(lfn: &mut String, lfp: &String, jpn: &String) {
+pub const LOG_FILE_PATTERN: &str = "{job}/Log_{date}.txt";
+
+pub fn construct_lf_name(lfn: &mut PathBuf, lfp: &str, jpn: &str) -> io::Result<()> {
 
 	let cli_args: Vec<String> = env::args().collect();
-	let cli_arg02 = &cli_args[1];
-	lfn.push_str(&cli_arg02.to_owned());
-	lfn.push_str(&jpn);
-	lfn.push_str(lfp);
-	
 	let right_now: String = chrono::Local::now().format("%Y%m%d-%H%M%S").to_string();
-	lfn.push_str(&right_now);
-	lfn.push_str(".txt");
-	
+	let file_pattern = lfp.replace("{job}", jpn).replace("{date}", &right_now);
+
+	lfn.clear();
+	lfn.push(&cli_args[1]);
+	for part in file_pattern.split(['/', '\\']).filter(|p| !p.is_empty()) {
+		lfn.push(part);
+	}
+
+	if let Some(log_dir) = lfn.parent() {
+		fs::create_dir_all(log_dir)?;
+	}
+
+	let first_choice = lfn.clone();
+	let mut suffix: u32 = 0;
+
+	loop {
+		match OpenOptions::new().write(true).create_new(true).open(&lfn) {
+			Ok(_) => return Ok(()),
+			Err(err) if err.kind() == io::ErrorKind::AlreadyExists => (),
+			Err(err) => return Err(err),
+		}
+		suffix += 1;
+		let stem = first_choice.file_stem().unwrap_or_default().to_string_lossy().to_string();
+		let mut file_name = format!("{}-{}", stem, suffix);
+		if let Some(ext) = first_choice.extension() {
+			file_name.push('.');
+			file_name.push_str(&ext.to_string_lossy());
+		}
+		lfn.set_file_name(file_name);
+	}
+}
+
+//	Logging options. The defaults match the original hard coded behaviour,
+//	they can be changed with Log keywords in the parameter file and then by
+//	--log options on the command line.
+//
+//	file_pattern - name of the log file under the log location, see
+//	               construct_lf_name
+//	level - the most detailed level written to the log
+//	echo - also write log records to stdout
+//	format - plain text lines or JSON lines
+//	module_levels - per module overrides of level, e.g. walkdir=warn
+//	retain_days - delete log files older than this many days, 0 keeps all
+//	retain_count - keep at most this many log files, 0 keeps all
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+	Text,
+	Json,
+}
+
+pub struct LogConfig {
+	pub file_pattern: String,
+	pub level: log::LevelFilter,
+	pub echo: bool,
+	pub format: LogFormat,
+	pub module_levels: Vec<(String, log::LevelFilter)>,
+	pub retain_days: u64,
+	pub retain_count: usize,
+}
+
+impl Default for LogConfig {
+
+	fn default() -> Self {
+		LogConfig {
+			file_pattern: String::from(LOG_FILE_PATTERN),
+			level: log::LevelFilter::Debug,
+			echo: false,
+			format: LogFormat::Text,
+			module_levels: Vec::new(),
+			retain_days: 0,
+			retain_count: 0,
+		}
+	}
+}
+
+impl LogConfig {
+
+	//	Apply a single logging keyword = value pair. Returns false if the
+	//	keyword is not a logging option or the value is not valid.
+
+	pub fn apply_parm(&mut self, key: &str, value: &str) -> bool {
+
+		match key {
+			"LogFilePattern" => {
+				if !value.contains("{date}") {
+					return false;
+				}
+				let ext = Path::new(value).extension().map(|e| e.to_string_lossy().to_lowercase());
+				if ext.as_deref() == Some("json") || ext.as_deref() == Some("html") {
+					return false;
+				}
+				self.file_pattern = value.to_string();
+			},
+			"LogLevel" => match value.parse::<log::LevelFilter>() {
+				Ok(level) => self.level = level,
+				Err(_) => return false,
+			},
+			"LogEcho" => return parse_flag(value, &mut self.echo),
+			"LogFormat" => match value.to_lowercase().as_str() {
+				"text" => self.format = LogFormat::Text,
+				"json" => self.format = LogFormat::Json,
+				_ => return false,
+			},
+			"LogModules" => {
+				for filter in value.split(',').filter(|f| !f.trim().is_empty()) {
+					let parts: Vec<&str> = filter.splitn(2, '=').collect();
+					if parts.len() != 2 {
+						return false;
+					}
+					match parts[1].trim().parse::<log::LevelFilter>() {
+						Ok(level) => self.module_levels.push((parts[0].trim().to_string(), level)),
+						Err(_) => return false,
+					}
+				}
+			},
+			"LogRetainDays" => return parse_parm(value, &mut self.retain_days),
+			"LogRetainCount" => return parse_parm(value, &mut self.retain_count),
+			_ => return false,
+		}
+
+		true
+	}
+
+	//	The logging options understood by apply_args.
+
+	pub const OPTIONS: &'static [&'static str] = &["--echo", "--no-echo", "--log-pattern", "--log-level",
+												  "--log-format", "--log-modules", "--log-retain-days",
+												  "--log-retain-count"];
+
+	//	Apply logging options given on the command line. These take the form
+	//	--log-pattern={job}/{date}.log, --log-level=info, --log-format=json,
+	//	--log-modules=walkdir=warn,
+	//	--log-retain-days=30, --log-retain-count=50, --echo and --no-echo.
+	//	Returns false if an option value is not valid.
+
+	pub fn apply_args(&mut self, cli_opts: &[String]) -> bool {
+
+		for opt in cli_opts {
+
+			let parts: Vec<&str> = opt.splitn(2, '=').collect();
+			let value = if parts.len() == 2 { parts[1] } else { "" };
+
+			let valid = match parts[0] {
+				"--echo" => { self.echo = true; true },
+				"--no-echo" => { self.echo = false; true },
+				"--log-pattern" => self.apply_parm("LogFilePattern", value),
+				"--log-level" => self.apply_parm("LogLevel", value),
+				"--log-format" => self.apply_parm("LogFormat", value),
+				"--log-modules" => self.apply_parm("LogModules", value),
+				"--log-retain-days" => self.apply_parm("LogRetainDays", value),
+				"--log-retain-count" => self.apply_parm("LogRetainCount", value),
+				_ => true,
+			};
+
+			if !valid {
+				println!("Invalid logging option {}", opt);
+				return false;
+			}
+		}
+
+		true
+	}
+}
+
+//	Read just the logging keywords from the parameter file. This is done
+//	before the log is opened so that the log can be set up as requested;
+//	the rest of the parameter file is processed once logging is running.
+//	Returns false if a Log keyword is not known or its value is not valid,
+//	after showing every such line.
+//
+//	Function parameters:
+//
+//	parm_file - name of the parameter file
+//	log_config - mutable reference to the callers logging options
+
+pub fn read_log_parms(parm_file: &str, log_config: &mut LogConfig) -> bool {
+
+	let fh = match File::open(parm_file) {
+		Ok(file) => file,
+		Err(_) => return true,
+	};
+
+	let mut valid = true;
+
+	for line in BufReader::new(fh).lines().map_while(Result::ok) {
+		let line = line.trim();
+		if line.starts_with("Log") {
+			let parts: Vec<&str> = line.splitn(2, '=').collect();
+			let value = if parts.len() == 2 { parts[1].trim() } else { "" };
+			if !log_config.apply_parm(parts[0].trim(), value) {
+				println!("Unknown keyword or invalid value: {}", line);
+				valid = false;
+			}
+		}
+	}
+
+	valid
+}
+
+//	Per file events are logged with structured fields as well as a message,
+//	for example
+//
+//	info!(event = "copy", source_path:% = src.display(), bytes = n; "Copied => ...");
+//
+//	Text logs show only the message. JSON logs add each field to the record
+//	so log shippers can aggregate events without parsing the message. The
+//	fields we use are event (copy, mkdir, delete, retry, error), source_path,
+//	target_path, bytes, duration_ms, kind and operation.
+
+struct JsonFields(String);
+
+impl<'kvs> log::kv::VisitSource<'kvs> for JsonFields {
+
+	fn visit_pair(&mut self, key: log::kv::Key<'kvs>, value: log::kv::Value<'kvs>)
+		-> Result<(), log::kv::Error> {
+
+		self.0.push_str(&format!(",\"{}\":", json_escape(key.as_str())));
+
+		if let Some(n) = value.to_u64() {
+			self.0.push_str(&n.to_string());
+		}
+		else if let Some(n) = value.to_i64() {
+			self.0.push_str(&n.to_string());
+		}
+		else if let Some(b) = value.to_bool() {
+			self.0.push_str(&b.to_string());
+		}
+		else {
+			self.0.push_str(&format!("\"{}\"", json_escape(&value.to_string())));
+		}
+
+		Ok(())
+	}
 }
 
 //	The following simple function is used to build a new logging instance. We
 //	are making use of the functionality provided by the fern crate. See the
 //	document provided in that crate at crate.io.
 //
+//	The same format is used for the log file and for stdout. Text records
+//	are written as date, time, target, level and message. JSON records are
+//	written one object per line with any event fields included.
 //
 //	Function parameters:
 //
 //	lfn - the name of the log file we want to create and write to.
+//	log_config - the logging options to apply.
 
-pub fn setup_logger(lfn: &str, stdflg: bool) -> Result<(), fern::InitError> {
+pub fn setup_logger(lfn: &Path, log_config: &LogConfig) -> Result<(), fern::InitError> {
 
-    let base_config = fern::Dispatch::new();
-	
-	let file_config = fern::Dispatch::new()
-        .format(|out, message, record| {
-            out.finish(format_args!(
-                "{} {} {} {}",
-                chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
-                record.target(),
-                record.level(),
-                message
-            ))
-        })
-        .level(log::LevelFilter::Debug)
-        .chain(fern::log_file(&lfn)?);
-
-	let stdo_config = fern::Dispatch::new()
-        .format(|out, message, record| {
-            out.finish(format_args!(
-                "{} {} {} {}",
-                chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
-                record.target(),
-                record.level(),
-                message
-            ))
+	let log_format = log_config.format;
+
+    let mut base_config = fern::Dispatch::new()
+        .format(move |out, message, record| {
+			match log_format {
+				LogFormat::Text => out.finish(format_args!(
+					"{} {} {} {}",
+					chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
+					record.target(),
+					record.level(),
+					message
+				)),
+				LogFormat::Json => {
+					let mut fields = JsonFields(String::new());
+					let _ = record.key_values().visit(&mut fields);
+					out.finish(format_args!(
+						"{{\"time\":\"{}\",\"target\":\"{}\",\"level\":\"{}\",\"message\":\"{}\"{}}}",
+						chrono::Local::now().format("%Y-%m-%dT%H:%M:%S%.3f%:z"),
+						json_escape(record.target()),
+						record.level(),
+						json_escape(&message.to_string()),
+						fields.0
+					))
+				},
+			}
         })
-        .level(log::LevelFilter::Debug)
-        .chain(std::io::stdout());
+        .level(log_config.level);
 
-	if stdflg {
-	base_config
-		.chain(file_config)
-		.chain(stdo_config)
-		.apply()?;
+	for (module, level) in &log_config.module_levels {
+		base_config = base_config.level_for(module.clone(), *level);
 	}
-	else {
-	base_config
-		.chain(file_config)
-		.apply()?;
-	}	
+
+	base_config = base_config.chain(fern::log_file(lfn)?);
+
+	if log_config.echo {
+		base_config = base_config.chain(std::io::stdout());
+	}
+
+	base_config.apply()?;
     Ok(())
 	
 }
 
+//	Remove old log files. Log files are those in the log directory whose
+//	names match the file name part of the log file pattern, that is they
+//	start with whatever comes before {date} and have the same extension.
+//	Files older than retain_days are removed, then the oldest are removed
+//	until no more than retain_count remain. The JSON and HTML reports that
+//	share a name with a log file go with it. The current log file is never
+//	removed.
+//
+//	Function parameters:
+//
+//	lfn - the name of the current log file
+//	jpn - the current job (program) name
+//	log_config - the logging options holding the retention settings
+
+pub fn prune_log_files(lfn: &Path, jpn: &str, log_config: &LogConfig) {
+
+	if log_config.retain_days == 0 && log_config.retain_count == 0 {
+		return;
+	}
+
+	let current_log = lfn.to_path_buf();
+	let log_dir = match current_log.parent() {
+		Some(log_dir) => log_dir.to_path_buf(),
+		None => return,
+	};
+
+	let (prefix, suffix) = log_name_parts(&log_config.file_pattern, jpn);
+	let log_ext = current_log.extension().map(|ext| ext.to_os_string()).unwrap_or_default();
+
+	let dir_entries = match fs::read_dir(&log_dir) {
+		Ok(dir_entries) => dir_entries,
+		Err(err) => {
+			warn!("Unable to read log directory {:?} {:?}", log_dir, err);
+			return;
+		}
+	};
+
+	let mut log_files = Vec::<(std::time::SystemTime, PathBuf)>::new();
+
+	for entry in dir_entries.flatten() {
+		let path = entry.path();
+		let is_log = path.file_name().is_some_and(|n| log_name_matches(&n.to_string_lossy(), &prefix, &suffix)) &&
+			path.extension().is_some_and(|ext| ext == log_ext);
+		if is_log && path != current_log {
+			if let Ok(modified) = entry.metadata().and_then(|m| m.modified()) {
+				log_files.push((modified, path));
+			}
+		}
+	}
+
+	log_files.sort();
+	log_files.reverse();
+
+	let now = std::time::SystemTime::now();
+	let max_age = std::time::Duration::from_secs(log_config.retain_days * 86400);
+
+	for (x, (modified, path)) in log_files.iter().enumerate() {
+
+		let too_old = log_config.retain_days > 0 &&
+			now.duration_since(*modified).is_ok_and(|age| age > max_age);
+		let too_many = log_config.retain_count > 0 && x + 1 >= log_config.retain_count;
+
+		if too_old || too_many {
+			remove_job_reports(path);
+			for ext in [log_ext.as_os_str(), "json".as_ref(), "html".as_ref()].iter() {
+				let old_file = path.with_extension(ext);
+				if old_file.exists() {
+					match fs::remove_file(&old_file) {
+						Ok(_) => info!("Removed old log file {:?}", old_file),
+						Err(err) => warn!("Unable to remove old log file {:?} {:?}", old_file, err),
+					}
+				}
+			}
+		}
+	}
+}
+
+//	Split the file name part of the log file pattern into the text before
+//	and after {date}, with {job} filled in.
+//
+//	Function parameters:
+//
+//	file_pattern - the log file pattern, see construct_lf_name
+//	jpn - the job or program name
+
+fn log_name_parts(file_pattern: &str, jpn: &str) -> (String, String) {
+
+	let name_pattern = file_pattern.replace("{job}", jpn);
+	let name_pattern = name_pattern.rsplit(['/', '\\']).next().unwrap_or("");
+	let mut pattern_parts = name_pattern.splitn(2, "{date}");
+	let prefix = pattern_parts.next().unwrap_or("");
+	let suffix = pattern_parts.next().unwrap_or("");
+
+	(prefix.to_string(), suffix.to_string())
+}
+
+//	Check whether a file name is one construct_lf_name could have made from
+//	the pattern. The {date} part has to be a date and time as written by
+//	construct_lf_name, optionally followed by the -1, -2 ... it adds when
+//	the name is taken, so other files in the log directory are never
+//	taken for old logs.
+
+fn log_name_matches(name: &str, prefix: &str, suffix: &str) -> bool {
+
+	let dated = match name.strip_prefix(prefix) {
+		Some(dated) => dated,
+		None => return false,
+	};
+
+	let (date, rest) = match (dated.get(..15), dated.get(15..)) {
+		(Some(date), Some(rest)) => (date, rest),
+		_ => return false,
+	};
+
+	if !date.char_indices().all(|(x, c)| if x == 8 { c == '-' } else { c.is_ascii_digit() }) {
+		return false;
+	}
+
+	if rest == suffix {
+		return true;
+	}
+
+//	The -1, -2 ... goes before the extension.
+
+	let (suffix_stem, suffix_ext) = match suffix.rfind('.') {
+		Some(dot) => suffix.split_at(dot),
+		None => (suffix, ""),
+	};
+
+	match rest.strip_prefix(suffix_stem).and_then(|r| r.strip_suffix(suffix_ext)) {
+		Some(count) => count.strip_prefix('-')
+			.is_some_and(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit())),
+		None => false,
+	}
+}
+
+//	Remove the reports of the named jobs of a run whose log is being
+//	removed. These are named as in job_report_name.
+
+fn remove_job_reports(lfn: &Path) {
+
+	let prefix = match lfn.file_stem() {
+		Some(stem) => format!("{}.", stem.to_string_lossy()),
+		None => return,
+	};
+
+	let dir_entries = match lfn.parent().map(fs::read_dir) {
+		Some(Ok(dir_entries)) => dir_entries,
+		_ => return,
+	};
+
+	for entry in dir_entries.flatten() {
+		let path = entry.path();
+		let is_report = path.extension().is_some_and(|ext| ext == "json" || ext == "html");
+		if is_report && entry.file_name().to_string_lossy().starts_with(&prefix) {
+			match fs::remove_file(&path) {
+				Ok(_) => info!("Removed old log file {:?}", path),
+				Err(err) => warn!("Unable to remove old log file {:?} {:?}", path, err),
+			}
+		}
+	}
+}
 
 //	Simple function to obtain file metadata. This implementation is
 //	specific to the Windows environment.
 //	We use the match construct so we can gracefully handle any error(s)
 //	that might occur. If we cannot obtain the metadata we will set the
-//	file creation time to zero.
+//	file creation time to zero and hand the error back to the caller.
 
 //	Function parameters:
 //	file_entry reference to a path buffer for the file we want to work on.
@@ -141,49 +572,3877 @@ pub fn get_meta(file_entry: &PathBuf,
 				my_creation_time: &mut u64,
 				my_access_time: &mut u64,
 				my_last_write_time: &mut u64,
-				my_filesize: &mut u64) {
+				my_filesize: &mut u64) -> io::Result<()> {
 	
-    let _metadata = match fs::metadata(file_entry) {
+    match fs::metadata(file_entry) {
 		Ok(_metadata) => {
 			*my_file_attrib = _metadata.file_attributes();
 			*my_creation_time = _metadata.creation_time();
 			*my_access_time = _metadata.last_access_time();
 			*my_last_write_time = _metadata.last_write_time();
-			*my_filesize = _metadata.file_size()
+			*my_filesize = _metadata.file_size();
+			Ok(())
 			} ,
 		Err(_metadata) => {
 			*my_creation_time = 0;
+			Err(_metadata)
 			}
-	} ;
+	}
 }
 
 //	Simple function to turn off the readonly setting on a file.
-//	Match construct is used to handle errors.
+//	Match construct is used to handle errors. Any error is also
+//	handed back to the caller so it can be accounted for.
 //
 //	Function parameters:
 //	file_entry reference to a path buffer for the file we want to work on.
 //	file_flag boolean used to indicate success or failure.
 
 pub fn make_file_writable(file_entry: &PathBuf,
-						  file_flag: &mut bool) {
+						  file_flag: &mut bool) -> io::Result<()> {
 						  
-	let _metadata = match fs::metadata(file_entry) {
+	match fs::metadata(file_entry) {
 	
 		Ok(_metadata) => {
 			let mut _my_perms = _metadata.permissions();
+			//	On Windows this only clears the read only attribute.
+			#[allow(clippy::permissions_set_readonly_false)]
 			_my_perms.set_readonly(false);
 			
-			let _my_result = match fs::set_permissions(file_entry, _my_perms) {
-				Ok(_my_result) => _my_result,
+			match fs::set_permissions(file_entry, _my_perms) {
+				Ok(_my_result) => Ok(_my_result),
 				Err(_my_result) => {
-					info!("fs::set_permissions error = {:?}", _my_result);
+					warn!("fs::set_permissions error = {:?}", _my_result);
 					*file_flag = false;
+					Err(_my_result)
 					}
-			};
+			}
 		},
 		Err(_metadata) => {
-			info!("Unable to obtain metadata for {:?}", file_entry);
+			warn!("Unable to obtain metadata for {:?}", file_entry);
 			*file_flag = false;
+			Err(_metadata)
+		}
+	}
+}
+
+//	The catalog records every file we copy so that old files can be located
+//	later without browsing the backup location. It lives in the backup base
+//	location and holds one tab separated line per copy:
+//
+//	run time <TAB> size <TAB> source path <TAB> target path
+//
+//	A tab, line break or % in a path is written as %09, %0A, %0D or %25 so
+//	that every record stays on one line with four fields.
+
+pub const CATALOG_NAME: &str = "sfbprp_catalog.txt";
+
+pub struct CatalogEntry {
+	pub run_time: String,
+	pub size: u64,
+	pub source: String,
+	pub target: String,
+}
+
+//	Search criteria for the find and history commands. Any criteria left as
+//	None are not applied. Dates are compared as YYYY-MM-DD strings.
+
+#[derive(Default)]
+pub struct CatalogQuery {
+	pub path_glob: Option<String>,
+	pub name_glob: Option<String>,
+	pub min_size: Option<u64>,
+	pub max_size: Option<u64>,
+	pub from_date: Option<String>,
+	pub to_date: Option<String>,
+}
+
+//	Open the catalog in the backup base location for appending. If we are
+//	unable to open it we log the reason and carry on without a catalog.
+//
+//	Function parameters:
+//
+//	target_base - the backup base location
+
+pub fn open_catalog(target_base: &str) -> Option<File> {
+
+	let mut cat_path = PathBuf::from(target_base);
+	cat_path.push(CATALOG_NAME);
+
+	match OpenOptions::new().create(true).append(true).open(&cat_path) {
+		Ok(file) => Some(file),
+		Err(err) => {
+			info!("Unable to open catalog {:?} {:?}", cat_path, err);
+			None
+		}
+	}
+}
+
+//	Append a single copy record to the catalog.
+//
+//	Function parameters:
+//
+//	cat_file - the catalog returned by open_catalog
+//	run_time - time stamp of the current run
+//	source - the file that was copied
+//	target - where the file was copied to
+//	size - number of bytes copied
+
+pub fn catalog_record(cat_file: &mut File,
+					  run_time: &str,
+					  source: &Path,
+					  target: &Path,
+					  size: u64) {
+
+	if let Err(err) = writeln!(cat_file, "{}\t{}\t{}\t{}",
+							   run_time,
+							   size,
+							   catalog_escape(&plain_path(source).display().to_string()),
+							   catalog_escape(&plain_path(target).display().to_string())) {
+		info!("Unable to write catalog record {:?}", err);
+	}
+}
+
+//	Escape the characters that would break a catalog record.
+
+fn catalog_escape(field: &str) -> String {
+
+	let mut escaped = String::with_capacity(field.len());
+
+	for c in field.chars() {
+		match c {
+			'%' => escaped.push_str("%25"),
+			'\t' => escaped.push_str("%09"),
+			'\n' => escaped.push_str("%0A"),
+			'\r' => escaped.push_str("%0D"),
+			_ => escaped.push(c),
+		}
+	}
+
+	escaped
+}
+
+//	Undo catalog_escape. Anything else that looks like an escape is left as
+//	it is, since records written before escaping was added may hold a %.
+
+fn catalog_unescape(field: &str) -> String {
+
+	field.replace("%09", "\t")
+		.replace("%0A", "\n")
+		.replace("%0D", "\r")
+		.replace("%25", "%")
+}
+
+//	Build a catalog query from the command line tokens that follow the find
+//	or history command. Each token is of the form key=value. Returns false
+//	if a token is not understood.
+//
+//	Function parameters:
+//
+//	tokens - command line tokens following the command
+//	query - mutable reference to the query we are building
+
+pub fn parse_catalog_query(tokens: &[String], query: &mut CatalogQuery) -> bool {
+
+	for token in tokens {
+
+		let parts: Vec<&str> = token.splitn(2, '=').collect();
+		if parts.len() != 2 {
+			println!("Expected key=value, found {}", token);
+			return false;
+		}
+
+		let value = parts[1].trim().to_string();
+
+		match parts[0].trim().to_lowercase().as_str() {
+			"path" => query.path_glob = Some(value),
+			"name" => query.name_glob = Some(value),
+			"from" => query.from_date = Some(value),
+			"to" => query.to_date = Some(value),
+			"minsize" | "maxsize" => {
+				let size = match value.parse::<u64>() {
+					Ok(size) => size,
+					Err(_) => {
+						println!("Invalid size {}", value);
+						return false;
+					}
+				};
+				if parts[0].trim().eq_ignore_ascii_case("minsize") {
+					query.min_size = Some(size);
+				}
+				else {
+					query.max_size = Some(size);
+				}
+			},
+			_ => {
+				println!("Unknown search criteria {}", parts[0]);
+				return false;
+			}
+		}
+	}
+
+	true
+}
+
+//	Read the catalog and return every entry that satisfies the query. The
+//	entries are returned in the order they were recorded.
+//
+//	Function parameters:
+//
+//	target_base - the backup base location holding the catalog
+//	query - the search criteria
+
+pub fn search_catalog(target_base: &str, query: &CatalogQuery) -> Vec<CatalogEntry> {
+
+	let mut found = Vec::<CatalogEntry>::new();
+	let mut cat_path = PathBuf::from(target_base);
+	cat_path.push(CATALOG_NAME);
+
+	let fh = match File::open(&cat_path) {
+		Ok(file) => file,
+		Err(err) => {
+			info!("Unable to open catalog {:?} {:?}", cat_path, err);
+			return found;
 		}
 	};
+
+	for line in BufReader::new(fh).lines() {
+
+		let line = match line {
+			Ok(line) => line,
+			Err(_) => continue,
+		};
+
+		let fields: Vec<&str> = line.split('\t').collect();
+		if fields.len() != 4 {
+			continue;
+		}
+
+		let entry = CatalogEntry {
+			run_time: fields[0].to_string(),
+			size: fields[1].parse::<u64>().unwrap_or(0),
+			source: catalog_unescape(fields[2]),
+			target: catalog_unescape(fields[3]),
+		};
+
+		if catalog_match(&entry, query) {
+			found.push(entry);
+		}
+	}
+
+	found
+}
+
+fn catalog_match(entry: &CatalogEntry, query: &CatalogQuery) -> bool {
+
+	let run_date = entry.run_time.get(..10).unwrap_or(&entry.run_time);
+
+	if let Some(glob) = &query.path_glob {
+		if !wildcard_match(glob, &entry.source) {
+			return false;
+		}
+	}
+
+	if let Some(glob) = &query.name_glob {
+		let file_name = PathBuf::from(&entry.source)
+			.file_name()
+			.map(|n| n.to_string_lossy().to_string())
+			.unwrap_or_default();
+		if !wildcard_match(glob, &file_name) {
+			return false;
+		}
+	}
+
+	if query.min_size.is_some_and(|min| entry.size < min) {
+		return false;
+	}
+
+	if query.max_size.is_some_and(|max| entry.size > max) {
+		return false;
+	}
+
+	if query.from_date.as_ref().is_some_and(|from| run_date < from.as_str()) {
+		return false;
+	}
+
+	if query.to_date.as_ref().is_some_and(|to| run_date > to.as_str()) {
+		return false;
+	}
+
+	true
+}
+
+//	Simple wildcard matcher supporting * and ?. The comparison ignores case
+//	since Windows file names are not case sensitive.
+//
+//	Function parameters:
+//
+//	pattern - the wildcard pattern
+//	text - the text we are testing
+
+pub fn wildcard_match(pattern: &str, text: &str) -> bool {
+
+	let pat: Vec<char> = pattern.to_lowercase().chars().collect();
+	let txt: Vec<char> = text.to_lowercase().chars().collect();
+
+	let mut p = 0;
+	let mut t = 0;
+	let mut star: Option<usize> = None;
+	let mut star_t = 0;
+
+	while t < txt.len() {
+		if p < pat.len() && (pat[p] == '?' || pat[p] == txt[t]) {
+			p += 1;
+			t += 1;
+		}
+		else if p < pat.len() && pat[p] == '*' {
+			star = Some(p);
+			star_t = t;
+			p += 1;
+		}
+		else if let Some(s) = star {
+			p = s + 1;
+			star_t += 1;
+			t = star_t;
+		}
+		else {
+			return false;
+		}
+	}
+
+	while p < pat.len() && pat[p] == '*' {
+		p += 1;
+	}
+
+	p == pat.len()
+}
+
+//	Summary of a single run. The same figures we write to the log at the end
+//	of a run are collected here so they can also be written out as a JSON
+//	report for monitoring tools to pick up.
+
+#[derive(Default)]
+pub struct RunReport {
+	pub program: String,
+	pub job: String,
+	pub run_start: String,
+	pub run_end: String,
+	pub target_base: String,
+	pub elapsed_secs: f64,
+	pub potential_backups: usize,
+	pub excluded_entries: usize,
+	pub dirs_created: i32,
+	pub files_copied: u64,
+	pub bytes_copied: u64,
+	pub files_moved: usize,
+	pub bytes_moved: u64,
+	pub mean_file_size: f64,
+	pub drives: Vec<(String, i32)>,
+	pub exclusions: Vec<(String, usize)>,
+	pub sources: Vec<SourceStats>,
+	pub largest_files: Vec<(String, u64)>,
+	pub orphans_found: usize,
+	pub orphans_removed: Vec<(String, u64)>,
+	pub dirs_removed: usize,
+	pub reconcile_notes: Vec<String>,
+	pub targets: Vec<TargetStats>,
+	pub errors: Vec<FileFailure>,
+}
+
+//	Per backup location figures for the run report. A file copied to two
+//	locations counts once in the run totals and once for each location.
+//	skipped holds the reason a location was not used, and is empty if it
+//	was.
+
+#[derive(Clone, Default)]
+pub struct TargetStats {
+	pub target_base: String,
+	pub skipped: String,
+	pub dirs_created: i32,
+	pub files_copied: u64,
+	pub bytes_copied: u64,
+	pub files_moved: usize,
+	pub orphans_removed: usize,
+	pub errors: usize,
+}
+
+//	Per source directory figures for the run report. skipped holds the
+//	reason a source was not available, and is empty if it was processed.
+
+#[derive(Default)]
+pub struct SourceStats {
+	pub root: String,
+	pub skipped: String,
+	pub entries: usize,
+	pub files_copied: u64,
+	pub bytes_copied: u64,
+}
+
+//	Broad classes of failure we report on. Operating system error codes
+//	are mapped onto these by classify_io_error.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FailureKind {
+	PermissionDenied,
+	InUse,
+	PathTooLong,
+	NotFound,
+	DiskFull,
+	Cancelled,
+	Network,
+	Other,
+}
+
+impl FailureKind {
+
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			FailureKind::PermissionDenied => "permission denied",
+			FailureKind::InUse => "in use",
+			FailureKind::PathTooLong => "path too long",
+			FailureKind::NotFound => "not found",
+			FailureKind::DiskFull => "disk full",
+			FailureKind::Cancelled => "cancelled",
+			FailureKind::Network => "network",
+			FailureKind::Other => "other",
+		}
+	}
+}
+
+//	A single failure encountered while processing a file or directory.
+//	operation names what we were doing, e.g. fs::copy or create_dir_all.
+
+pub struct FileFailure {
+	pub kind: FailureKind,
+	pub operation: String,
+	pub path: String,
+	pub message: String,
+}
+
+//	Work out which class of failure an I/O error belongs to. We look at the
+//	raw operating system error first since the standard library does not
+//	give sharing violations, long paths or full disks their own ErrorKind.
+//
+//	Function parameters:
+//
+//	err - the error returned by the failing operation
+
+pub fn classify_io_error(err: &io::Error) -> FailureKind {
+
+	#[cfg(windows)]
+	let by_code = match err.raw_os_error() {
+		Some(5) => Some(FailureKind::PermissionDenied),		// ERROR_ACCESS_DENIED
+		Some(32) | Some(33) => Some(FailureKind::InUse),	// ERROR_SHARING_VIOLATION, ERROR_LOCK_VIOLATION
+		Some(111) | Some(206) => Some(FailureKind::PathTooLong),	// ERROR_BUFFER_OVERFLOW, ERROR_FILENAME_EXCED_RANGE
+		Some(2) | Some(3) => Some(FailureKind::NotFound),	// ERROR_FILE_NOT_FOUND, ERROR_PATH_NOT_FOUND
+		Some(39) | Some(112) => Some(FailureKind::DiskFull),	// ERROR_HANDLE_DISK_FULL, ERROR_DISK_FULL
+		Some(53) | Some(59) | Some(64) | Some(67) =>			// ERROR_BAD_NETPATH, ERROR_UNEXP_NET_ERR,
+			Some(FailureKind::Network),							// ERROR_NETNAME_DELETED, ERROR_BAD_NET_NAME
+		Some(121) | Some(1231) | Some(1232) =>					// ERROR_SEM_TIMEOUT, ERROR_NETWORK_UNREACHABLE,
+			Some(FailureKind::Network),							// ERROR_HOST_UNREACHABLE
+		_ => None,
+	};
+
+	#[cfg(unix)]
+	let by_code = match err.raw_os_error() {
+		Some(1) | Some(13) => Some(FailureKind::PermissionDenied),	// EPERM, EACCES
+		Some(16) | Some(26) => Some(FailureKind::InUse),	// EBUSY, ETXTBSY
+		Some(36) => Some(FailureKind::PathTooLong),		// ENAMETOOLONG
+		Some(2) => Some(FailureKind::NotFound),			// ENOENT
+		Some(28) | Some(122) => Some(FailureKind::DiskFull),	// ENOSPC, EDQUOT
+		Some(101) | Some(104) | Some(110) | Some(112) | Some(113) | Some(116) =>
+			Some(FailureKind::Network),		// ENETUNREACH, ECONNRESET, ETIMEDOUT, EHOSTDOWN, EHOSTUNREACH, ESTALE
+		_ => None,
+	};
+
+	if let Some(kind) = by_code {
+		return kind;
+	}
+
+	match err.kind() {
+		io::ErrorKind::PermissionDenied => FailureKind::PermissionDenied,
+		io::ErrorKind::NotFound => FailureKind::NotFound,
+		io::ErrorKind::Interrupted => FailureKind::Cancelled,
+		io::ErrorKind::TimedOut | io::ErrorKind::ConnectionReset |
+		io::ErrorKind::ConnectionAborted => FailureKind::Network,
+		_ => FailureKind::Other,
+	}
+}
+
+//	Escape a string so it can be placed inside double quotes in JSON.
+
+pub fn json_escape(text: &str) -> String {
+
+	let mut escaped = String::with_capacity(text.len() + 2);
+
+	for c in text.chars() {
+		match c {
+			'"' => escaped.push_str("\\\""),
+			'\\' => escaped.push_str("\\\\"),
+			'\n' => escaped.push_str("\\n"),
+			'\r' => escaped.push_str("\\r"),
+			'\t' => escaped.push_str("\\t"),
+			c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+			c => escaped.push(c),
+		}
+	}
+
+	escaped
+}
+
+//	Number of largest copied files kept in the run report, and the number
+//	of earlier runs shown in the history chart of the HTML report.
+
+pub const LARGEST_FILES: usize = 10;
+pub const HISTORY_RUNS: usize = 14;
+
+//	Lay out a list of already rendered JSON values as an array.
+
+fn json_array(items: &[String]) -> String {
+
+	if items.is_empty() {
+		String::from("[]")
+	}
+	else {
+		format!("[\n{}\n  ]", items.join(",\n"))
+	}
+}
+
+impl RunReport {
+
+	//	Classify and log a failure and add it to the list of errors for
+	//	the run. Files that have gone missing or are in use are usually
+	//	transient so they are logged as warnings, everything else as an
+	//	error.
+
+	pub fn record_failure(&mut self, operation: &str, path: &Path, err: &io::Error) {
+
+		let kind = classify_io_error(err);
+
+		match kind {
+			FailureKind::NotFound | FailureKind::InUse =>
+				warn!(event = "error", kind = kind.as_str(), operation = operation, source_path:% = path.display();
+					  "{} {} {:?} ({})", operation, path.display(), err, kind.as_str()),
+			_ =>
+				error!(event = "error", kind = kind.as_str(), operation = operation, source_path:% = path.display();
+					   "{} {} {:?} ({})", operation, path.display(), err, kind.as_str()),
+		}
+
+		self.errors.push(FileFailure {
+			kind,
+			operation: operation.to_string(),
+			path: path.display().to_string(),
+			message: err.to_string(),
+		});
+	}
+
+	//	Record an error returned while walking a directory tree.
+
+	pub fn record_walk_failure(&mut self, err: &walkdir::Error) {
+
+		let path = err.path().map(|p| p.to_path_buf()).unwrap_or_default();
+
+		match err.io_error() {
+			Some(io_err) => self.record_failure("walkdir", &path, io_err),
+			None => {
+				error!(event = "error", kind = FailureKind::Other.as_str(), operation = "walkdir", source_path:% = path.display();
+					   "walkdir {} {}", path.display(), err);
+				self.errors.push(FileFailure {
+					kind: FailureKind::Other,
+					operation: String::from("walkdir"),
+					path: path.display().to_string(),
+					message: err.to_string(),
+				});
+			}
+		}
+	}
+
+	//	Log a table of every failure in the run, preceded by a count of
+	//	failures of each kind.
+
+	pub fn log_failure_summary(&self) {
+
+		if self.errors.is_empty() {
+			info!("No failures recorded");
+			return;
+		}
+
+		let mut kinds: Vec<FailureKind> = self.errors.iter().map(|e| e.kind).collect();
+		kinds.sort();
+		kinds.dedup();
+
+		error!("{} failure(s) recorded", self.errors.len());
+		for kind in &kinds {
+			error!("{:>8} {}", self.errors.iter().filter(|e| e.kind == *kind).count(), kind.as_str());
+		}
+
+		error!("{:<18} {:<16} {}", "Failure", "Operation", "Path");
+		for failure in &self.errors {
+			error!("{:<18} {:<16} {}", failure.kind.as_str(), failure.operation, failure.path);
+		}
+	}
+
+	//	Account for a file copied during the run against its source
+	//	directory. The source roots are held in their plain form for the
+	//	reports, so the path is compared in that form too; an extended path
+	//	never starts with a plain one.
+
+	pub fn record_copy(&mut self, path: &Path, size: u64) {
+
+		let path = plain_path(path);
+
+		if let Some(source) = self.sources.iter_mut().find(|s| path.starts_with(&s.root)) {
+			source.files_copied += 1;
+			source.bytes_copied += size;
+		}
+
+		self.record_largest(&path, size);
+	}
+
+	//	Keep track of the largest files copied during the run. The list is
+	//	held in descending order of size and trimmed to LARGEST_FILES.
+
+	pub fn record_largest(&mut self, path: &Path, size: u64) {
+
+		if self.largest_files.len() == LARGEST_FILES &&
+			self.largest_files[LARGEST_FILES - 1].1 >= size {
+			return;
+		}
+
+		let x = self.largest_files.iter().position(|f| f.1 < size)
+			.unwrap_or(self.largest_files.len());
+		self.largest_files.insert(x, (path.display().to_string(), size));
+		self.largest_files.truncate(LARGEST_FILES);
+	}
+
+	//	Render the report as a JSON document.
+
+	pub fn to_json(&self) -> String {
+
+		let mut json = String::with_capacity(4096);
+
+		json.push_str("{\n");
+		json.push_str(&format!("  \"program\": \"{}\",\n", json_escape(&self.program)));
+		json.push_str(&format!("  \"job\": \"{}\",\n", json_escape(&self.job)));
+		json.push_str(&format!("  \"run_start\": \"{}\",\n", json_escape(&self.run_start)));
+		json.push_str(&format!("  \"run_end\": \"{}\",\n", json_escape(&self.run_end)));
+		json.push_str(&format!("  \"target_base\": \"{}\",\n", json_escape(&self.target_base)));
+		json.push_str(&format!("  \"elapsed_secs\": {:.2},\n", self.elapsed_secs));
+		json.push_str(&format!("  \"potential_backups\": {},\n", self.potential_backups));
+		json.push_str(&format!("  \"excluded_entries\": {},\n", self.excluded_entries));
+		json.push_str(&format!("  \"dirs_created\": {},\n", self.dirs_created));
+		json.push_str(&format!("  \"files_copied\": {},\n", self.files_copied));
+		json.push_str(&format!("  \"bytes_copied\": {},\n", self.bytes_copied));
+		json.push_str(&format!("  \"files_moved\": {},\n", self.files_moved));
+		json.push_str(&format!("  \"bytes_moved\": {},\n", self.bytes_moved));
+		json.push_str(&format!("  \"kilobytes_copied\": {:.2},\n", self.bytes_copied as f64 / 1024.0));
+		json.push_str(&format!("  \"megabytes_copied\": {:.2},\n", self.bytes_copied as f64 / 1048576.0));
+		json.push_str(&format!("  \"gigabytes_copied\": {:.2},\n", self.bytes_copied as f64 / 1073741824.0));
+		json.push_str(&format!("  \"mean_file_size\": {:.2},\n", self.mean_file_size));
+
+		let drives: Vec<String> = self.drives.iter()
+			.map(|(id, ct)| format!("    {{ \"drive\": \"{}\", \"directories\": {} }}", json_escape(id), ct))
+			.collect();
+		json.push_str(&format!("  \"drives\": {},\n", json_array(&drives)));
+
+		let exclusions: Vec<String> = self.exclusions.iter()
+			.map(|(path, ct)| format!("    {{ \"path\": \"{}\", \"excluded\": {} }}", json_escape(path), ct))
+			.collect();
+		json.push_str(&format!("  \"exclusions\": {},\n", json_array(&exclusions)));
+
+		let sources: Vec<String> = self.sources.iter()
+			.map(|s| format!("    {{ \"root\": \"{}\", \"skipped\": \"{}\", \"entries\": {}, \"files_copied\": {}, \"bytes_copied\": {} }}",
+							 json_escape(&s.root), json_escape(&s.skipped),
+							 s.entries, s.files_copied, s.bytes_copied))
+			.collect();
+		json.push_str(&format!("  \"sources\": {},\n", json_array(&sources)));
+
+		let largest: Vec<String> = self.largest_files.iter()
+			.map(|(path, size)| format!("    {{ \"path\": \"{}\", \"bytes\": {} }}", json_escape(path), size))
+			.collect();
+		json.push_str(&format!("  \"largest_files\": {},\n", json_array(&largest)));
+
+		json.push_str(&format!("  \"orphans_found\": {},\n", self.orphans_found));
+		let orphans: Vec<String> = self.orphans_removed.iter()
+			.map(|(path, size)| format!("    {{ \"path\": \"{}\", \"bytes\": {} }}", json_escape(path), size))
+			.collect();
+		json.push_str(&format!("  \"orphans_removed\": {},\n", json_array(&orphans)));
+		json.push_str(&format!("  \"dirs_removed\": {},\n", self.dirs_removed));
+
+		let targets: Vec<String> = self.targets.iter()
+			.map(|t| format!("    {{ \"target_base\": \"{}\", \"skipped\": \"{}\", \"dirs_created\": {}, \"files_copied\": {}, \"bytes_copied\": {}, \"files_moved\": {}, \"orphans_removed\": {}, \"errors\": {} }}",
+							 json_escape(&t.target_base), json_escape(&t.skipped), t.dirs_created,
+							 t.files_copied, t.bytes_copied, t.files_moved, t.orphans_removed, t.errors))
+			.collect();
+		json.push_str(&format!("  \"targets\": {},\n", json_array(&targets)));
+
+		let notes: Vec<String> = self.reconcile_notes.iter()
+			.map(|n| format!("    \"{}\"", json_escape(n)))
+			.collect();
+		json.push_str(&format!("  \"reconcile_notes\": {},\n", json_array(&notes)));
+
+		let errors: Vec<String> = self.errors.iter()
+			.map(|e| format!("    {{ \"kind\": \"{}\", \"operation\": \"{}\", \"path\": \"{}\", \"message\": \"{}\" }}",
+							 e.kind.as_str(), json_escape(&e.operation),
+							 json_escape(&e.path), json_escape(&e.message)))
+			.collect();
+		json.push_str(&format!("  \"errors\": {}\n", json_array(&errors)));
+
+		json.push_str("}\n");
+		json
+	}
+}
+
+//	Write the run report as JSON alongside the log file. The report has the
+//	same name as the log file with a .json extension.
+//
+//	Function parameters:
+//
+//	lfn - the name of the log file for this run
+//	report - the completed run report
+
+pub fn write_json_report(lfn: &Path, report: &RunReport) {
+
+	let report_name = lfn.with_extension("json");
+
+	match fs::write(&report_name, report.to_json()) {
+		Ok(_) => info!("Run report written to {:?}", report_name),
+		Err(err) => info!("Unable to write run report {:?} {:?}", report_name, err),
+	}
+}
+
+//	Pull the value of a top level field out of one of our own JSON reports.
+//	This is not a general JSON parser; it relies on the layout written by
+//	RunReport::to_json where every scalar field sits on its own line.
+//
+//	Function parameters:
+//
+//	json - the text of the report
+//	key - the name of the field we want
+
+pub fn json_field(json: &str, key: &str) -> Option<String> {
+
+	let wanted = format!("\"{}\":", key);
+
+	for line in json.lines() {
+		let line = line.trim();
+		if line.starts_with(&wanted) {
+			let value = line[wanted.len()..].trim().trim_end_matches(',').trim();
+			return Some(value.trim_matches('"').to_string());
+		}
+	}
+
+	None
+}
+
+//	Read the JSON reports of earlier runs from the log directory so we can
+//	show a history of recent runs of the same job. Returns (run start,
+//	files, bytes) for up to HISTORY_RUNS runs, oldest first.
+//
+//	Function parameters:
+//
+//	lfn - the name the reports for this run are written under
+//	job - the name of the job
+
+pub fn read_report_history(lfn: &Path, job: &str) -> Vec<(String, u64, u64)> {
+
+	let mut history = Vec::<(String, u64, u64)>::new();
+	let this_report = lfn.with_extension("json");
+
+	let log_dir = match this_report.parent() {
+		Some(log_dir) => log_dir.to_path_buf(),
+		None => return history,
+	};
+
+	let dir_entries = match fs::read_dir(&log_dir) {
+		Ok(dir_entries) => dir_entries,
+		Err(err) => {
+			info!("Unable to read report history {:?} {:?}", log_dir, err);
+			return history;
+		}
+	};
+
+	for entry in dir_entries.flatten() {
+
+		let path = entry.path();
+		if path == this_report ||
+			path.extension().is_none_or(|ext| ext != "json") {
+			continue;
+		}
+
+		if let Ok(json) = fs::read_to_string(&path) {
+			if json_field(&json, "job").unwrap_or_default() != job {
+				continue;
+			}
+			if let Some(run_start) = json_field(&json, "run_start") {
+				let files = json_field(&json, "files_copied")
+					.and_then(|v| v.parse::<u64>().ok()).unwrap_or(0);
+				let bytes = json_field(&json, "bytes_copied")
+					.and_then(|v| v.parse::<u64>().ok()).unwrap_or(0);
+				history.push((run_start, files, bytes));
+			}
+		}
+	}
+
+	history.sort();
+	if history.len() > HISTORY_RUNS {
+		history.drain(..history.len() - HISTORY_RUNS);
+	}
+
+	history
+}
+
+//	Escape a string for inclusion in HTML.
+
+pub fn html_escape(text: &str) -> String {
+
+	text.replace('&', "&amp;")
+		.replace('<', "&lt;")
+		.replace('>', "&gt;")
+		.replace('"', "&quot;")
+}
+
+//	Format a byte count using the largest sensible unit.
+
+pub fn format_bytes(bytes: u64) -> String {
+
+	let value = bytes as f64;
+
+	if value <= 1024.0 {
+		format!("{} bytes", bytes)
+	}
+	else if value <= 1048576.0 {
+		format!("{:.2} KB", value / 1024.0)
+	}
+	else if value <= 1073741824.0 {
+		format!("{:.2} MB", value / 1048576.0)
+	}
+	else {
+		format!("{:.2} GB", value / 1073741824.0)
+	}
+}
+
+//	Write a self contained HTML summary of the run next to the log file.
+//	The page holds the run totals, per drive statistics, the largest files
+//	copied, any errors, and a bar chart of bytes copied by recent runs taken
+//	from the JSON reports of earlier runs.
+//
+//	Function parameters:
+//
+//	lfn - the name of the log file for this run
+//	report - the completed run report
+
+pub fn write_html_report(lfn: &Path, report: &RunReport) {
+
+	let report_name = lfn.with_extension("html");
+	let mut history = read_report_history(lfn, &report.job);
+	history.push((report.run_start.clone(), report.files_copied, report.bytes_copied));
+
+	let mut html = String::with_capacity(16384);
+
+	html.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+	html.push_str(&format!("<title>{} run {}</title>\n",
+						   html_escape(&report.program), html_escape(&report.run_start)));
+	html.push_str("<style>\n\
+		body { font-family: Segoe UI, Arial, sans-serif; margin: 2em; color: #222; }\n\
+		table { border-collapse: collapse; margin-bottom: 1.5em; }\n\
+		th, td { border: 1px solid #ccc; padding: 4px 10px; text-align: left; }\n\
+		th { background: #eee; }\n\
+		td.num { text-align: right; }\n\
+		.error { color: #a00; }\n\
+		</style>\n</head>\n<body>\n");
+
+	html.push_str(&format!("<h1>{} backup run</h1>\n", html_escape(&report.program)));
+
+	html.push_str("<h2>Totals</h2>\n<table>\n");
+	let totals = [
+		("Started", report.run_start.clone()),
+		("Finished", report.run_end.clone()),
+		("Backup location", report.target_base.clone()),
+		("Elapsed", format!("{:.2} seconds", report.elapsed_secs)),
+		("Potential backups", report.potential_backups.to_string()),
+		("Excluded entries", report.excluded_entries.to_string()),
+		("Directories created", report.dirs_created.to_string()),
+		("Files copied", report.files_copied.to_string()),
+		("Data copied", format_bytes(report.bytes_copied)),
+		("Files moved in backup", report.files_moved.to_string()),
+		("Data moved in backup", format_bytes(report.bytes_moved)),
+		("Average file size", format_bytes(report.mean_file_size as u64)),
+		("Orphans found", report.orphans_found.to_string()),
+		("Orphans removed", report.orphans_removed.len().to_string()),
+		("Directories removed", report.dirs_removed.to_string()),
+		("Errors", report.errors.len().to_string()),
+	];
+	for (label, value) in totals.iter() {
+		html.push_str(&format!("<tr><th>{}</th><td>{}</td></tr>\n", label, html_escape(value)));
+	}
+	html.push_str("</table>\n");
+
+	html.push_str("<h2>Drives</h2>\n<table>\n<tr><th>Drive</th><th>Source directories</th></tr>\n");
+	for (id, ct) in &report.drives {
+		html.push_str(&format!("<tr><td>{}</td><td class=\"num\">{}</td></tr>\n", html_escape(id), ct));
+	}
+	html.push_str("</table>\n");
+
+	html.push_str("<h2>Sources</h2>\n<table>\n\
+		<tr><th>Source</th><th>Entries</th><th>Files copied</th><th>Data copied</th><th>Skipped</th></tr>\n");
+	for source in &report.sources {
+		html.push_str(&format!("<tr><td>{}</td><td class=\"num\">{}</td><td class=\"num\">{}</td><td class=\"num\">{}</td><td class=\"error\">{}</td></tr>\n",
+							   html_escape(&source.root), source.entries,
+							   source.files_copied, format_bytes(source.bytes_copied),
+							   html_escape(&source.skipped)));
+	}
+	html.push_str("</table>\n");
+
+	html.push_str("<h2>Backup locations</h2>\n<table>\n\
+		<tr><th>Location</th><th>Directories created</th><th>Files copied</th><th>Data copied</th>\
+		<th>Files moved</th><th>Orphans removed</th><th>Errors</th><th>Skipped</th></tr>\n");
+	for target in &report.targets {
+		html.push_str(&format!("<tr><td>{}</td><td class=\"num\">{}</td><td class=\"num\">{}</td><td class=\"num\">{}</td>\
+							   <td class=\"num\">{}</td><td class=\"num\">{}</td><td class=\"num\">{}</td><td class=\"error\">{}</td></tr>\n",
+							   html_escape(&target.target_base), target.dirs_created, target.files_copied,
+							   format_bytes(target.bytes_copied), target.files_moved, target.orphans_removed,
+							   target.errors, html_escape(&target.skipped)));
+	}
+	html.push_str("</table>\n");
+
+	html.push_str("<h2>Largest files copied</h2>\n<table>\n<tr><th>File</th><th>Size</th></tr>\n");
+	for (path, size) in &report.largest_files {
+		html.push_str(&format!("<tr><td>{}</td><td class=\"num\">{}</td></tr>\n",
+							   html_escape(path), format_bytes(*size)));
+	}
+	html.push_str("</table>\n");
+
+	html.push_str(&format!("<h2>Orphans removed ({})</h2>\n", report.orphans_removed.len()));
+	for note in &report.reconcile_notes {
+		html.push_str(&format!("<p class=\"error\">{}</p>\n", html_escape(note)));
+	}
+	if !report.orphans_removed.is_empty() {
+		html.push_str("<table>\n<tr><th>File</th><th>Size</th></tr>\n");
+		for (path, size) in &report.orphans_removed {
+			html.push_str(&format!("<tr><td>{}</td><td class=\"num\">{}</td></tr>\n",
+								   html_escape(path), format_bytes(*size)));
+		}
+		html.push_str("</table>\n");
+	}
+
+	html.push_str(&format!("<h2>Errors ({})</h2>\n", report.errors.len()));
+	if report.errors.is_empty() {
+		html.push_str("<p>None</p>\n");
+	}
+	else {
+		html.push_str("<table>\n<tr><th>Failure</th><th>Operation</th><th>Path</th><th>Message</th></tr>\n");
+		for error in &report.errors {
+			html.push_str(&format!("<tr class=\"error\"><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+								   error.kind.as_str(), html_escape(&error.operation),
+								   html_escape(&error.path), html_escape(&error.message)));
+		}
+		html.push_str("</table>\n");
+	}
+
+	html.push_str("<h2>Recent runs</h2>\n");
+	html.push_str(&history_chart(&history));
+
+	html.push_str("</body>\n</html>\n");
+
+	match fs::write(&report_name, html) {
+		Ok(_) => info!("HTML report written to {:?}", report_name),
+		Err(err) => info!("Unable to write HTML report {:?} {:?}", report_name, err),
+	}
+}
+
+//	Build an inline SVG bar chart of bytes copied for each run in history.
+
+fn history_chart(history: &[(String, u64, u64)]) -> String {
+
+	let bar_width = 40;
+	let chart_height = 200;
+	let width = history.len() * (bar_width + 10) + 10;
+	let max_bytes = history.iter().map(|h| h.2).max().unwrap_or(0).max(1);
+
+	let mut svg = format!("<svg width=\"{}\" height=\"{}\" xmlns=\"http://www.w3.org/2000/svg\">\n",
+						  width, chart_height + 60);
+
+	for (x, (run_start, files, bytes)) in history.iter().enumerate() {
+		let height = (*bytes as f64 / max_bytes as f64 * chart_height as f64) as usize;
+		let left = 10 + x * (bar_width + 10);
+		svg.push_str(&format!("<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"#4a7ebb\">\
+							   <title>{} - {} files, {}</title></rect>\n",
+							  left, chart_height - height, bar_width, height.max(1),
+							  html_escape(run_start), files, format_bytes(*bytes)));
+		svg.push_str(&format!("<text x=\"{}\" y=\"{}\" font-size=\"10\" transform=\"rotate(45 {} {})\">{}</text>\n",
+							  left, chart_height + 12, left, chart_height + 12,
+							  html_escape(run_start.get(..10).unwrap_or(run_start))));
+	}
+
+	svg.push_str("</svg>\n");
+	svg
+}
+
+//	Tuning options for a run. These are read from the parameter file and
+//	the command line, with the defaults set in RunParms::default. Not all
+//	defaults match how the program behaved before the option existed; a
+//	failed copy, for example, is now retried 3 times and then deferred.
+//
+//	retry_count - number of times a transient copy failure is retried
+//	retry_delay_ms - delay before the first retry, doubled for each retry
+//	retry_deferred - retry files that still fail once the run is complete
+//	progress - how progress is shown while copying
+//	progress_interval - seconds between progress lines in the log
+//	copy_options - chunk size and resume settings for the copy engine
+//	throttle_rate - bytes per second limit for copying, 0 for no limit
+//	throttle_window - (start, end) minutes after midnight during which the
+//	                  limit applies, None to apply it all the time
+//	lock_stale_hours - a run lock older than this is taken to be stale, 0
+//	                   to rely only on checking the holding process
+//	reconcile - remove backup files whose source file no longer exists
+//	max_delete_percent - abort reconciliation if more than this percentage
+//	                     of the backup files would be removed, 0 for no limit
+//	max_delete_count - abort reconciliation if more than this many files
+//	                   would be removed, 0 for no limit
+//	force_delete - go ahead with reconciliation even if a limit is exceeded
+//	volume_check - what to do when a source or target volume is not the one
+//	               recorded in the backup location
+//	accept_volumes - record the current volumes as the expected ones
+//	detect_moves - when reconciling, rename an orphaned backup file to the
+//	               target of a new source file that matches it instead of
+//	               copying the file again and removing the orphan
+//	move_min_size - files smaller than this are just copied
+//	move_verify - compare the contents of a possible move before using it
+//	case_mode - whether names that differ only in case are the same name
+//	keep_empty_dirs - leave empty directories in the backup when reconciling
+//	                  if their source directory still exists
+//	attribute_filter - which entries to leave out for their attributes
+//	link_policy - how symbolic links and junctions in the sources are handled
+//	free_space_margin - bytes to leave free on the target after copying
+//	free_space_abort - end the run before copying if the target does not
+//	                   have room for the plan, otherwise just warn
+
+pub struct RunParms {
+	pub retry_count: u32,
+	pub retry_delay_ms: u64,
+	pub retry_deferred: bool,
+	pub progress: ProgressMode,
+	pub progress_interval: u64,
+	pub copy_options: CopyOptions,
+	pub throttle_rate: u64,
+	pub throttle_window: Option<(u32, u32)>,
+	pub lock_stale_hours: u64,
+	pub reconcile: bool,
+	pub max_delete_percent: f64,
+	pub max_delete_count: usize,
+	pub force_delete: bool,
+	pub volume_check: VolumeCheck,
+	pub accept_volumes: bool,
+	pub free_space_margin: u64,
+	pub free_space_abort: bool,
+	pub link_policy: LinkPolicy,
+	pub attribute_filter: AttributeFilter,
+	pub keep_empty_dirs: bool,
+	pub case_mode: CaseMode,
+	pub detect_moves: bool,
+	pub move_min_size: u64,
+	pub move_verify: bool,
+}
+
+impl Default for RunParms {
+
+	fn default() -> Self {
+		RunParms {
+			retry_count: 3,
+			retry_delay_ms: 500,
+			retry_deferred: true,
+			progress: ProgressMode::Auto,
+			progress_interval: 60,
+			copy_options: CopyOptions::default(),
+			throttle_rate: 0,
+			throttle_window: None,
+			lock_stale_hours: 24,
+			reconcile: false,
+			max_delete_percent: 10.0,
+			max_delete_count: 1000,
+			force_delete: false,
+			volume_check: VolumeCheck::Refuse,
+			accept_volumes: false,
+			free_space_margin: 100 * 1024 * 1024,
+			free_space_abort: true,
+			link_policy: LinkPolicy::Skip,
+			attribute_filter: AttributeFilter::default(),
+			keep_empty_dirs: true,
+			case_mode: CaseMode::Auto,
+			detect_moves: true,
+			move_min_size: 1024 * 1024,
+			move_verify: false,
+		}
+	}
+}
+
+impl RunParms {
+
+	//	Apply a single keyword = value pair from the parameter file. The
+	//	logging, job, source and target keywords are handled elsewhere and
+	//	never get here. Returns false if the keyword is not known or the
+	//	value is not valid.
+
+	pub fn apply_parm(&mut self, key: &str, value: &str) -> bool {
+
+		match key {
+			"RetryCount" => parse_parm(value, &mut self.retry_count),
+			"RetryDelay" => parse_parm(value, &mut self.retry_delay_ms),
+			"RetryDeferred" => parse_flag(value, &mut self.retry_deferred),
+			"Progress" => match value.to_lowercase().as_str() {
+				"auto" => { self.progress = ProgressMode::Auto; true },
+				"terminal" => { self.progress = ProgressMode::Terminal; true },
+				"log" => { self.progress = ProgressMode::Log; true },
+				"off" => { self.progress = ProgressMode::Off; true },
+				_ => false,
+			},
+			"ProgressInterval" => parse_parm(value, &mut self.progress_interval),
+			"CopyChunkSize" => {
+				let mut chunk_size: u64 = 0;
+				if !parse_size(value, &mut chunk_size) || chunk_size == 0 {
+					return false;
+				}
+				self.copy_options.chunk_size = chunk_size as usize;
+				true
+			},
+			"ResumeMinSize" => parse_size(value, &mut self.copy_options.resume_min_size),
+			"ThrottleRate" => parse_size(value, &mut self.throttle_rate),
+			"LockStaleHours" => parse_parm(value, &mut self.lock_stale_hours),
+			"Reconcile" => parse_flag(value, &mut self.reconcile),
+			"MaxDeletePercent" => parse_parm(value, &mut self.max_delete_percent),
+			"MaxDeleteCount" => parse_parm(value, &mut self.max_delete_count),
+			"FreeSpaceMargin" => parse_size(value, &mut self.free_space_margin),
+			"FreeSpaceAction" => match value.to_lowercase().as_str() {
+				"abort" => { self.free_space_abort = true; true },
+				"warn" => { self.free_space_abort = false; true },
+				_ => false,
+			},
+			"LinkPolicy" => match value.to_lowercase().as_str() {
+				"skip" => { self.link_policy = LinkPolicy::Skip; true },
+				"copy" => { self.link_policy = LinkPolicy::Copy; true },
+				"follow" => { self.link_policy = LinkPolicy::Follow; true },
+				_ => false,
+			},
+			"DetectMoves" => parse_flag(value, &mut self.detect_moves),
+			"MoveMinSize" => parse_size(value, &mut self.move_min_size),
+			"MoveVerify" => parse_flag(value, &mut self.move_verify),
+			"CaseMode" => match value.to_lowercase().as_str() {
+				"auto" => { self.case_mode = CaseMode::Auto; true },
+				"sensitive" => { self.case_mode = CaseMode::Sensitive; true },
+				"insensitive" => { self.case_mode = CaseMode::Insensitive; true },
+				_ => false,
+			},
+			"KeepEmptyDirs" => parse_flag(value, &mut self.keep_empty_dirs),
+			"SkipTemporary" => parse_flag(value, &mut self.attribute_filter.skip_temporary),
+			"SkipOffline" => parse_flag(value, &mut self.attribute_filter.skip_offline),
+			"SkipHidden" => parse_flag(value, &mut self.attribute_filter.skip_hidden),
+			"SkipSystem" => parse_flag(value, &mut self.attribute_filter.skip_system),
+			"SkipSpecial" => parse_flag(value, &mut self.attribute_filter.skip_special),
+			"VolumeCheck" => match value.to_lowercase().as_str() {
+				"off" => { self.volume_check = VolumeCheck::Off; true },
+				"warn" => { self.volume_check = VolumeCheck::Warn; true },
+				"refuse" => { self.volume_check = VolumeCheck::Refuse; true },
+				_ => false,
+			},
+			"ThrottleSchedule" => {
+				if value.eq_ignore_ascii_case("always") {
+					self.throttle_window = None;
+					return true;
+				}
+				let times: Vec<Option<u32>> = value.splitn(2, '-').map(parse_time_of_day).collect();
+				match times.as_slice() {
+					[Some(start), Some(end)] => {
+						self.throttle_window = Some((*start, *end));
+						true
+					},
+					_ => false,
+				}
+			},
+			_ => false,
+		}
+	}
+
+	//	The run options understood by apply_args.
+
+	pub const OPTIONS: &'static [&'static str] = &["--progress", "--throttle", "--reconcile", "--no-reconcile",
+												  "--force-delete", "--accept-volumes"];
+
+	//	Apply run options given on the command line, which take the form
+	//	--progress=terminal or --throttle=2M, or are flags such as
+	//	--force-delete. Returns false if an option value is not valid.
+
+	pub fn apply_args(&mut self, cli_opts: &[String]) -> bool {
+
+		for opt in cli_opts {
+
+			let parts: Vec<&str> = opt.splitn(2, '=').collect();
+			let value = if parts.len() == 2 { parts[1] } else { "" };
+
+			let valid = match parts[0] {
+				"--progress" => self.apply_parm("Progress", value),
+				"--throttle" => self.apply_parm("ThrottleRate", value),
+				"--reconcile" => { self.reconcile = true; true },
+				"--no-reconcile" => { self.reconcile = false; true },
+				"--force-delete" => { self.force_delete = true; true },
+				"--accept-volumes" => { self.accept_volumes = true; true },
+				_ => true,
+			};
+
+			if !valid {
+				info!("Invalid option {}", opt);
+				return false;
+			}
+		}
+
+		true
+	}
+}
+
+//	Parse a numeric parameter value into the callers variable.
+
+pub fn parse_parm<T: std::str::FromStr>(value: &str, parm: &mut T) -> bool {
+
+	match value.parse::<T>() {
+		Ok(v) => {
+			*parm = v;
+			true
+		},
+		Err(_) => false,
+	}
+}
+
+//	Parse a size such as 500K, 10M or 1G into a number of bytes.
+
+pub fn parse_size(value: &str, parm: &mut u64) -> bool {
+
+	let value = value.trim().to_uppercase();
+	let value = value.trim_end_matches('B');
+	let (digits, multiplier) = match value.chars().last() {
+		Some('K') => (&value[..value.len() - 1], 1024),
+		Some('M') => (&value[..value.len() - 1], 1024 * 1024),
+		Some('G') => (&value[..value.len() - 1], 1024 * 1024 * 1024),
+		_ => (value, 1),
+	};
+
+	match digits.trim().parse::<u64>().ok().and_then(|v| v.checked_mul(multiplier)) {
+		Some(v) => {
+			*parm = v;
+			true
+		},
+		None => false,
+	}
+}
+
+//	Parse a time of day given as hh:mm into minutes after midnight.
+
+pub fn parse_time_of_day(value: &str) -> Option<u32> {
+
+	let parts: Vec<&str> = value.trim().splitn(2, ':').collect();
+	let hours = parts.first()?.parse::<u32>().ok()?;
+	let minutes = match parts.get(1) {
+		Some(m) => m.parse::<u32>().ok()?,
+		None => 0,
+	};
+
+	if hours > 24 || minutes > 59 || hours * 60 + minutes > 1440 {
+		return None;
+	}
+
+	Some(hours * 60 + minutes)
+}
+
+//	Parse a Yes/No parameter value into the callers variable.
+
+pub fn parse_flag(value: &str, parm: &mut bool) -> bool {
+
+	match value.to_lowercase().as_str() {
+		"yes" | "true" | "y" | "1" => *parm = true,
+		"no" | "false" | "n" | "0" => *parm = false,
+		_ => return false,
+	}
+
+	true
+}
+
+//	Failures that are worth trying again. A file in use may be released and
+//	network shares often recover, anything else will fail the same way.
+
+pub fn is_transient(kind: FailureKind) -> bool {
+
+	kind == FailureKind::InUse || kind == FailureKind::Network
+}
+
+//	Copy a file with the copy engine, retrying transient failures with
+//	exponential backoff. A large file that fails part way through carries
+//	on from where it stopped on the next attempt. Only the targets that
+//	failed are tried again. Returns the result for each target.
+//
+//	Function parameters:
+//
+//	source - the file to copy
+//	targets - where to copy it to
+//	parms - run parameters holding the retry and copy settings
+//	throttle - bandwidth limit applied to the copy
+//	progress - progress callback passed on to copy_file_chunked
+
+pub fn copy_with_retry(source: &PathBuf,
+					   targets: &[PathBuf],
+					   parms: &RunParms,
+					   throttle: &mut Throttle,
+					   progress: &mut dyn FnMut(u64) -> bool) -> Vec<io::Result<u64>> {
+
+	let mut results: Vec<Option<io::Result<u64>>> = targets.iter().map(|_| None).collect();
+	let mut pending: Vec<usize> = (0..targets.len()).collect();
+	let mut delay = parms.retry_delay_ms;
+	let mut attempt: u32 = 0;
+
+	loop {
+		let paths: Vec<PathBuf> = pending.iter().map(|x| targets[*x].clone()).collect();
+		let attempt_results = copy_file_chunked(source, &paths, &parms.copy_options, throttle, progress);
+		let mut retry = Vec::<usize>::new();
+
+		for (x, result) in pending.iter().zip(attempt_results) {
+			match result {
+				Err(err) if attempt < parms.retry_count && is_transient(classify_io_error(&err)) => {
+					warn!(event = "retry", source_path:% = source.display(), target_path:% = targets[*x].display(),
+						  duration_ms = delay;
+						  "Retry {} of {} for {:?} in {} ms ({})",
+						  attempt + 1, parms.retry_count, source, delay, err);
+					retry.push(*x);
+				},
+				other => results[*x] = Some(other),
+			}
+		}
+
+		if retry.is_empty() {
+			break;
+		}
+
+		attempt += 1;
+		std::thread::sleep(std::time::Duration::from_millis(delay));
+		delay = delay.saturating_mul(2);
+		pending = retry;
+	}
+
+	results.into_iter()
+		.map(|result| result.unwrap_or_else(|| Err(io::Error::other("not copied"))))
+		.collect()
+}
+
+//	Bandwidth limit for copying. The limit is shared by all the files we
+//	copy so that the overall rate stays under bytes_per_sec. If a window
+//	is given the limit only applies between its start and end times; a
+//	window such as 18:00-08:00 runs past midnight.
+
+pub struct Throttle {
+	bytes_per_sec: u64,
+	window: Option<(u32, u32)>,
+	start: std::time::Instant,
+	sent: u64,
+}
+
+impl Throttle {
+
+	pub fn new(bytes_per_sec: u64, window: Option<(u32, u32)>) -> Throttle {
+
+		Throttle {
+			bytes_per_sec,
+			window,
+			start: std::time::Instant::now(),
+			sent: 0,
+		}
+	}
+
+	//	Is the limit in force right now?
+
+	pub fn is_active(&self) -> bool {
+
+		if self.bytes_per_sec == 0 {
+			return false;
+		}
+
+		match self.window {
+			None => true,
+			Some((start, end)) => {
+				let now = chrono::Local::now();
+				let minute = chrono::Timelike::hour(&now) * 60 + chrono::Timelike::minute(&now);
+				if start <= end {
+					minute >= start && minute < end
+				}
+				else {
+					minute >= start || minute < end
+				}
+			}
+		}
+	}
+
+	//	Account for bytes just written and sleep long enough to keep the
+	//	rate under the limit. The measuring window is restarted every few
+	//	seconds so that idle time does not build up into a burst.
+
+	pub fn pace(&mut self, bytes: u64) {
+
+		if !self.is_active() {
+			return;
+		}
+
+		self.sent += bytes;
+
+		let expected = std::time::Duration::from_secs_f64(self.sent as f64 / self.bytes_per_sec as f64);
+		let actual = self.start.elapsed();
+
+		if expected > actual {
+			std::thread::sleep(expected - actual);
+		}
+
+		if self.start.elapsed() > std::time::Duration::from_secs(5) {
+			self.start = std::time::Instant::now();
+			self.sent = 0;
+		}
+	}
+}
+
+//	The copy engine. Files are copied in chunks of chunk_size bytes through
+//	a buf_redux reader. After every chunk the bandwidth limit is applied and
+//	the progress callback is told how many bytes were written; if the
+//	callback returns false the copy is cancelled.
+//
+//	Every file is copied to a partial file alongside the target (the target
+//	name plus PARTIAL_SUFFIX) which is renamed over the target once
+//	complete, so a copy that fails never leaves a truncated file in place of
+//	the last good backup. If a copy of a file of at least resume_min_size
+//	bytes is cut short the partial file is left behind, and the next attempt
+//	carries on from where it stopped as long as the source has not been
+//	modified since. Smaller partial files are removed. A resume_min_size of
+//	0 turns resuming off.
+
+pub const COPY_CHUNK: usize = 1024 * 1024;
+pub const RESUME_MIN_SIZE: u64 = 64 * 1024 * 1024;
+pub const PARTIAL_SUFFIX: &str = ".sfbpart";
+
+pub struct CopyOptions {
+	pub chunk_size: usize,
+	pub resume_min_size: u64,
+}
+
+impl Default for CopyOptions {
+
+	fn default() -> Self {
+		CopyOptions {
+			chunk_size: COPY_CHUNK,
+			resume_min_size: RESUME_MIN_SIZE,
+		}
+	}
+}
+
+//	Name of the partial file used while copying to target.
+
+pub fn partial_name(target: &Path) -> PathBuf {
+
+	let mut name = target.as_os_str().to_os_string();
+	name.push(PARTIAL_SUFFIX);
+	PathBuf::from(name)
+}
+
+//	Copy a file using the copy engine to one or more targets. The source is
+//	read once and each chunk is written to every target, so a job with
+//	several backup locations reads its sources only once. A target that
+//	fails is dropped and the others carry on. The permissions and times of
+//	the source are applied to each target so it compares equal on the next
+//	run. Returns the size of the file, or the error, for each target.
+//
+//	Function parameters:
+//
+//	source - the file to copy
+//	targets - where to copy it to
+//	options - chunk size and resume settings
+//	throttle - the bandwidth limit
+//	progress - called with the number of bytes read after each chunk,
+//	           returns false to cancel the copy
+
+pub fn copy_file_chunked(source: &Path,
+						 targets: &[PathBuf],
+						 options: &CopyOptions,
+						 throttle: &mut Throttle,
+						 progress: &mut dyn FnMut(u64) -> bool) -> Vec<io::Result<u64>> {
+
+	let source_meta = match fs::metadata(source) {
+		Ok(meta) => meta,
+		Err(err) => return targets.iter().map(|_| Err(copy_error(&err))).collect(),
+	};
+	let source_len = source_meta.len();
+	let resumable = options.resume_min_size > 0 && source_len >= options.resume_min_size;
+	let write_paths: Vec<PathBuf> = targets.iter().map(|target| partial_name(target)).collect();
+
+//	See if there are partial copies we can carry on from. Each must be no
+//	longer than the source and written after the source was last changed.
+//	Every target carries on from the shortest of them, so one target with
+//	no usable partial copy means starting again.
+
+	let mut resume_from: u64 = 0;
+
+	if resumable {
+		resume_from = u64::MAX;
+		for write_path in &write_paths {
+			let usable = match fs::metadata(write_path) {
+				Ok(partial_meta) => {
+					let unchanged = match (source_meta.modified(), partial_meta.modified()) {
+						(Ok(source_time), Ok(partial_time)) => partial_time >= source_time,
+						_ => false,
+					};
+					if unchanged && partial_meta.len() <= source_len { partial_meta.len() } else { 0 }
+				},
+				Err(_) => 0,
+			};
+			resume_from = resume_from.min(usable);
+		}
+		if resume_from > 0 {
+			info!("Resuming {:?} at {} of {} bytes", source, resume_from, source_len);
+		}
+	}
+
+	let mut source_file = match File::open(source) {
+		Ok(file) => file,
+		Err(err) => return targets.iter().map(|_| Err(copy_error(&err))).collect(),
+	};
+
+	if resume_from > 0 {
+		if let Err(err) = io::Seek::seek(&mut source_file, io::SeekFrom::Start(resume_from)) {
+			return targets.iter().map(|_| Err(copy_error(&err))).collect();
+		}
+	}
+
+	let mut errors: Vec<Option<io::Error>> = targets.iter().map(|_| None).collect();
+	let mut writers: Vec<Option<File>> = Vec::with_capacity(targets.len());
+
+	for (x, write_path) in write_paths.iter().enumerate() {
+		let opened = if resume_from > 0 {
+			OpenOptions::new().write(true).open(write_path).and_then(|mut file| {
+				file.set_len(resume_from)?;
+				io::Seek::seek(&mut file, io::SeekFrom::End(0))?;
+				Ok(file)
+			})
+		}
+		else {
+			File::create(write_path)
+		};
+		match opened {
+			Ok(file) => writers.push(Some(file)),
+			Err(err) => {
+				errors[x] = Some(err);
+				writers.push(None);
+			}
+		}
+	}
+
+	let mut reader = buf_redux::BufReader::with_capacity(options.chunk_size.max(4096), source_file);
+	let mut copied = resume_from;
+	let mut source_error: Option<io::Error> = None;
+	let mut cancelled = resume_from > 0 && !progress(resume_from);
+
+	while !cancelled && writers.iter().any(Option::is_some) {
+		let n = {
+			let chunk = match io::BufRead::fill_buf(&mut reader) {
+				Ok(chunk) => chunk,
+				Err(err) => {
+					source_error = Some(err);
+					break;
+				}
+			};
+			if chunk.is_empty() {
+				break;
+			}
+			for (x, writer) in writers.iter_mut().enumerate() {
+				if let Some(file) = writer {
+					if let Err(err) = file.write_all(chunk) {
+						errors[x] = Some(err);
+						*writer = None;
+					}
+				}
+			}
+			chunk.len()
+		};
+		io::BufRead::consume(&mut reader, n);
+		copied += n as u64;
+		throttle.pace(n as u64);
+		cancelled = !progress(n as u64);
+	}
+
+	let mut results = Vec::<io::Result<u64>>::with_capacity(targets.len());
+
+	for (x, writer) in writers.into_iter().enumerate() {
+		let result = match writer {
+			None => Err(errors[x].take().unwrap_or_else(|| io::Error::other("not copied"))),
+			Some(mut file) => {
+				if let Err(err) = file.flush() {
+					Err(err)
+				}
+				else if let Some(err) = &source_error {
+					Err(copy_error(err))
+				}
+				else if cancelled {
+					Err(io::Error::new(io::ErrorKind::Interrupted, "copy cancelled"))
+				}
+				else {
+					drop(file);
+					finish_copy(&source_meta, &write_paths[x], &targets[x], copied)
+				}
+			},
+		};
+		if result.is_err() && !resumable {
+			let _ = fs::remove_file(&write_paths[x]);
+		}
+		results.push(result);
+	}
+
+	results
+}
+
+//	Put a completed copy in place and give it the times and permissions of
+//	the source. Times are set first since a read only target cannot have
+//	its times changed on Windows.
+
+fn finish_copy(source_meta: &fs::Metadata, write_path: &Path, target: &Path, copied: u64) -> io::Result<u64> {
+
+	fs::rename(write_path, target)?;
+
+	filetime::set_file_times(target,
+							 filetime::FileTime::from_last_access_time(source_meta),
+							 filetime::FileTime::from_last_modification_time(source_meta))?;
+	fs::set_permissions(target, source_meta.permissions())?;
+
+	Ok(copied)
+}
+
+//	Make a copy of an error, such as a read error on the source that has to
+//	be reported against every target. The OS error code is kept so the
+//	copy classifies the same way.
+
+fn copy_error(err: &io::Error) -> io::Error {
+
+	match err.raw_os_error() {
+		Some(code) => io::Error::from_raw_os_error(code),
+		None => io::Error::new(err.kind(), err.to_string()),
+	}
+}
+
+//	Cancelling a run. A run is cancelled when a file named STOP_FILE_NAME
+//	appears in any of the backup base locations. We look for it at most once a
+//	second so the check does not slow the copy down. Once found it is
+//	renamed to STOPPED_FILE_NAME, so that it cancels this run and not every
+//	later one as well.
+
+pub const STOP_FILE_NAME: &str = "sfbprp.stop";
+pub const STOPPED_FILE_NAME: &str = "sfbprp.stopped";
+
+//	The check is made often during a run, so it is also where the run locks
+//	we hold are refreshed, every LOCK_REFRESH_SECS.
+
+pub struct CancelCheck {
+	stop_files: Vec<PathBuf>,
+	lock_files: Vec<PathBuf>,
+	last_check: std::time::Instant,
+	last_refresh: std::time::Instant,
+	cancelled: bool,
+}
+
+impl CancelCheck {
+
+	pub fn new(target_bases: &[String], lock_files: Vec<PathBuf>) -> CancelCheck {
+
+		CancelCheck {
+			stop_files: target_bases.iter().map(|base| Path::new(base).join(STOP_FILE_NAME)).collect(),
+			lock_files,
+			last_check: std::time::Instant::now(),
+			last_refresh: std::time::Instant::now(),
+			cancelled: false,
+		}
+	}
+
+	pub fn cancelled(&mut self) -> bool {
+
+		if self.last_refresh.elapsed() >= std::time::Duration::from_secs(LOCK_REFRESH_SECS) {
+			self.last_refresh = std::time::Instant::now();
+			for lock_file in &self.lock_files {
+				refresh_run_lock(lock_file);
+			}
+		}
+
+		if !self.cancelled && self.last_check.elapsed() >= std::time::Duration::from_secs(1) {
+			self.last_check = std::time::Instant::now();
+			if let Some(stop_file) = self.stop_files.iter().find(|stop_file| stop_file.exists()) {
+				warn!("Found {:?}, cancelling the run", stop_file);
+				self.cancelled = true;
+				let stopped = stop_file.with_file_name(STOPPED_FILE_NAME);
+				match fs::rename(stop_file, &stopped) {
+					Ok(_) => info!("Renamed {:?} to {:?} so later runs are not cancelled", stop_file, stopped),
+					Err(err) => warn!("Unable to rename {:?}, remove it before the next run {:?}", stop_file, err),
+				}
+			}
+		}
+
+		self.cancelled
+	}
+}
+
+//	A file that is to be copied, and the backup locations it is to be
+//	copied to.
+
+pub struct CopyItem {
+	pub source: PathBuf,
+	pub size: u64,
+	pub targets: Vec<CopyTarget>,
+}
+
+//	One place a file is to be copied to. index is the position of the backup
+//	location in the job. readonly is set when the existing target copy is
+//	read only and has to be made writable before it is replaced.
+
+pub struct CopyTarget {
+	pub index: usize,
+	pub path: PathBuf,
+	pub readonly: bool,
+}
+
+//	How progress is shown while copying. Auto uses the terminal when stderr,
+//	where the status line is drawn, is an interactive terminal and the log
+//	otherwise.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProgressMode {
+	Auto,
+	Terminal,
+	Log,
+	Off,
+}
+
+//	Progress of the copy phase measured against the copy plan. In terminal
+//	mode a single status line is redrawn on stderr; in log mode a progress
+//	line is logged every interval seconds.
+
+pub struct Progress {
+	mode: ProgressMode,
+	interval: std::time::Duration,
+	files_total: usize,
+	bytes_total: u64,
+	files_done: usize,
+	bytes_done: u64,
+	file_start_bytes: u64,
+	current: String,
+	start: std::time::Instant,
+	last_update: std::time::Instant,
+}
+
+impl Progress {
+
+	pub fn new(mode: ProgressMode, interval_secs: u64, files_total: usize, bytes_total: u64) -> Progress {
+
+		let mode = match mode {
+			ProgressMode::Auto => {
+				if io::stderr().is_terminal() { ProgressMode::Terminal } else { ProgressMode::Log }
+			},
+			mode => mode,
+		};
+
+		let now = std::time::Instant::now();
+
+		Progress {
+			mode,
+			interval: if mode == ProgressMode::Terminal {
+				std::time::Duration::from_millis(250)
+			} else {
+				std::time::Duration::from_secs(interval_secs.max(1))
+			},
+			files_total,
+			bytes_total,
+			files_done: 0,
+			bytes_done: 0,
+			file_start_bytes: 0,
+			current: String::new(),
+			start: now,
+			last_update: now,
+		}
+	}
+
+	//	Note the file we are starting on.
+
+	pub fn start_file(&mut self, path: &Path) {
+
+		self.file_start_bytes = self.bytes_done;
+		self.current = path.display().to_string();
+		self.update(false);
+	}
+
+	//	Note bytes copied so far within the current file.
+
+	pub fn add_bytes(&mut self, bytes: u64) {
+
+		self.bytes_done += bytes;
+		self.update(false);
+	}
+
+	//	Note that the current file is finished. bytes is the size of the
+	//	file, which replaces whatever was reported through add_bytes.
+
+	pub fn file_done(&mut self, bytes: u64) {
+
+		self.files_done += 1;
+		self.bytes_done = self.file_start_bytes + bytes;
+		self.update(false);
+	}
+
+	//	Show the final figures and, in terminal mode, end the status line.
+
+	pub fn finish(&mut self) {
+
+		self.current.clear();
+		self.update(true);
+
+		if self.mode == ProgressMode::Terminal {
+			eprintln!();
+		}
+	}
+
+	fn update(&mut self, force: bool) {
+
+		if self.mode == ProgressMode::Off ||
+			(!force && self.last_update.elapsed() < self.interval) {
+			return;
+		}
+
+		self.last_update = std::time::Instant::now();
+
+		let elapsed = self.start.elapsed().as_secs_f64();
+		let rate = if elapsed > 0.0 { self.bytes_done as f64 / elapsed } else { 0.0 };
+		let eta = if rate > 0.0 && self.bytes_total > self.bytes_done {
+			format_duration((self.bytes_total - self.bytes_done) as f64 / rate)
+		} else {
+			String::from("--:--:--")
+		};
+
+		let status = format!("{}/{} files, {} of {}, {}/s, ETA {}",
+							 self.files_done, self.files_total,
+							 format_bytes(self.bytes_done), format_bytes(self.bytes_total),
+							 format_bytes(rate as u64), eta);
+
+		match self.mode {
+			ProgressMode::Terminal => {
+				let mut current = self.current.clone();
+				if current.chars().count() > 40 {
+					let tail: String = current.chars().rev().take(37).collect::<Vec<char>>()
+						.into_iter().rev().collect();
+					current = format!("...{}", tail);
+				}
+				eprint!("\r{} {:<40}", status, current);
+				let _ = io::stderr().flush();
+			},
+			ProgressMode::Log => info!("Progress: {}", status),
+			_ => (),
+		}
+	}
+}
+
+//	Format a number of seconds as hh:mm:ss.
+
+pub fn format_duration(secs: f64) -> String {
+
+	let secs = secs.max(0.0) as u64;
+	format!("{:02}:{:02}:{:02}", secs / 3600, (secs / 60) % 60, secs % 60)
+}
+
+//	The run lock. A lock file in the backup base location records which
+//	process on which machine is working on it and when it started. The file
+//	is created with create_new so only one run can ever create it. A lock is
+//	stale if it was left by a process on this machine that is no longer
+//	running, or if it was left by another machine and has not been touched
+//	for longer than the stale limit; stale locks are removed and taken
+//	over. A lock held by a process still running on this machine is never
+//	stale, however old. The holder touches the lock file every
+//	LOCK_REFRESH_SECS while it runs, so only a machine that has gone quiet
+//	leaves an old lock behind. The lock file is removed when RunLock is
+//	dropped.
+
+pub const LOCK_FILE_NAME: &str = "sfbprp.lock";
+pub const LOCK_REFRESH_SECS: u64 = 600;
+
+pub struct RunLock {
+	path: PathBuf,
+}
+
+impl RunLock {
+
+	pub fn path(&self) -> &Path {
+		&self.path
+	}
+}
+
+//	Touch a lock file we hold so other machines can see the run is alive.
+
+pub fn refresh_run_lock(lock_path: &Path) {
+
+	if let Err(err) = filetime::set_file_mtime(lock_path, filetime::FileTime::now()) {
+		warn!("Unable to refresh run lock {:?} {:?}", lock_path, err);
+	}
+}
+
+impl Drop for RunLock {
+
+	fn drop(&mut self) {
+		if let Err(err) = fs::remove_file(&self.path) {
+			warn!("Unable to remove run lock {:?} {:?}", self.path, err);
+		}
+	}
+}
+
+pub enum LockError {
+	Held(String),
+	Io(io::Error),
+}
+
+//	Name of this machine, used to tell whether a lock holder is local.
+
+pub fn host_name() -> String {
+
+	if let Ok(name) = env::var("COMPUTERNAME") {
+		return name;
+	}
+	if let Ok(name) = env::var("HOSTNAME") {
+		return name;
+	}
+	fs::read_to_string("/etc/hostname")
+		.map(|name| name.trim().to_string())
+		.unwrap_or_else(|_| String::from("unknown"))
+}
+
+//	Is the process with this id still running on this machine?
+
+#[cfg(unix)]
+pub fn process_running(pid: u32) -> bool {
+
+	Path::new(&format!("/proc/{}", pid)).exists()
+}
+
+#[cfg(windows)]
+pub fn process_running(pid: u32) -> bool {
+
+	match process::Command::new("tasklist")
+		.args(["/FI", &format!("PID eq {}", pid), "/NH"])
+		.output() {
+		Ok(output) => String::from_utf8_lossy(&output.stdout).contains(&pid.to_string()),
+		Err(_) => true,
+	}
+}
+
+//	Take the run lock for a backup location.
+//
+//	Function parameters:
+//
+//	target_base - the backup base location
+//	stale_hours - age after which a lock is taken to be stale, 0 for never
+
+pub fn acquire_run_lock(target_base: &str, stale_hours: u64) -> Result<RunLock, LockError> {
+
+	let lock_path = Path::new(target_base).join(LOCK_FILE_NAME);
+	let this_host = host_name();
+
+	for _attempt in 0..2 {
+
+		match OpenOptions::new().write(true).create_new(true).open(&lock_path) {
+			Ok(mut lock_file) => {
+				let contents = format!("pid = {}\nhost = {}\nstarted = {}\n",
+									   process::id(), this_host,
+									   chrono::Local::now().format("%Y-%m-%d %H:%M:%S"));
+				lock_file.write_all(contents.as_bytes()).map_err(LockError::Io)?;
+				info!("Run lock taken {:?}", lock_path);
+				return Ok(RunLock { path: lock_path });
+			},
+			Err(err) if err.kind() == io::ErrorKind::AlreadyExists => (),
+			Err(err) => return Err(LockError::Io(err)),
+		}
+
+//	Someone holds the lock. Find out who and whether it is stale.
+
+		let contents = fs::read_to_string(&lock_path).unwrap_or_default();
+		let mut pid: Option<u32> = None;
+		let mut host = String::new();
+		let mut started = String::new();
+
+		for line in contents.lines() {
+			let parts: Vec<&str> = line.splitn(2, '=').collect();
+			if parts.len() == 2 {
+				match parts[0].trim() {
+					"pid" => pid = parts[1].trim().parse::<u32>().ok(),
+					"host" => host = parts[1].trim().to_string(),
+					"started" => started = parts[1].trim().to_string(),
+					_ => (),
+				}
+			}
+		}
+
+		let holder = format!("pid {} on {} since {}",
+							 pid.map_or(String::from("?"), |p| p.to_string()), host, started);
+
+		let local = host.eq_ignore_ascii_case(&this_host);
+		let running = pid.map(process_running);
+		let dead_process = local && running == Some(false);
+		let live_process = local && running == Some(true);
+
+		let too_old = stale_hours > 0 && fs::metadata(&lock_path)
+			.and_then(|m| m.modified())
+			.ok()
+			.and_then(|m| m.elapsed().ok())
+			.is_some_and(|age| age.as_secs() > stale_hours * 3600);
+
+		if !dead_process && (live_process || !too_old) {
+			return Err(LockError::Held(holder));
+		}
+
+		warn!("Removing stale run lock {:?} held by {}", lock_path, holder);
+		if let Err(err) = fs::remove_file(&lock_path) {
+			return Err(LockError::Io(err));
+		}
+	}
+
+	Err(LockError::Held(String::from("lock could not be taken")))
+}
+
+//	Build the target path for a source path. The colon is removed from the
+//	drive and the result is placed under the backup base location, so that
+//	C:\Users\bill\x.txt is backed up to <base>\C\Users\bill\x.txt. A
+//	share \\server\share is backed up under <base>\UNC\server\share, and
+//	on Unix /home/bill/x.txt goes to <base>/home/bill/x.txt. The path is
+//	built from its components so names that are not valid UTF-8 are kept
+//	as they are, and on Windows the result has the extended length prefix
+//	so paths longer than 260 characters work.
+//
+//	Function parameters:
+//
+//	target_base - the backup base location
+//	source - the source file or directory
+
+pub fn build_target_path(target_base: &str, source: &Path) -> PathBuf {
+
+	let mut target = extended_path(Path::new(target_base));
+
+	for component in source.components() {
+		match component {
+			Component::Prefix(prefix) => match prefix.kind() {
+				Prefix::Disk(drive) | Prefix::VerbatimDisk(drive) => {
+					target.push((drive as char).to_string());
+				},
+				Prefix::UNC(server, share) | Prefix::VerbatimUNC(server, share) => {
+					target.push("UNC");
+					target.push(server);
+					target.push(share);
+				},
+				Prefix::Verbatim(name) | Prefix::DeviceNS(name) => target.push(name),
+			},
+			Component::RootDir => (),
+			other => target.push(other.as_os_str()),
+		}
+	}
+
+	target
+}
+
+//	The reverse of build_target_path. Given a path in the backup location
+//	work out the source path it was copied from. Returns None if the path
+//	is not under the backup base location.
+//
+//	Function parameters:
+//
+//	target_base - the backup base location
+//	target - a file or directory in the backup location
+
+#[cfg(windows)]
+pub fn source_path_for(target_base: &str, target: &Path) -> Option<PathBuf> {
+
+	let base = extended_path(Path::new(target_base));
+	let rest = extended_path(target);
+	let mut rest = rest.strip_prefix(&base).ok()?.components();
+
+	let first = rest.next()?.as_os_str().to_str()?.to_string();
+	let source = if first == "UNC" {
+		let server = rest.next()?.as_os_str().to_str()?.to_string();
+		let share = rest.next()?.as_os_str().to_str()?.to_string();
+		PathBuf::from(format!("\\\\{}\\{}\\", server, share))
+	}
+	else if first.len() == 1 {
+		PathBuf::from(format!("{}:\\", first))
+	}
+	else {
+		return None;
+	};
+
+	Some(extended_path(&source.join(rest.as_path())))
+}
+
+#[cfg(not(windows))]
+pub fn source_path_for(target_base: &str, target: &Path) -> Option<PathBuf> {
+
+	let rest = target.strip_prefix(target_base).ok()?;
+
+	Some(Path::new("/").join(rest))
+}
+
+//	Add the extended length prefix to an absolute Windows path, so \\?\C:\x
+//	for C:\x and \\?\UNC\server\share\x for \\server\share\x. The path is
+//	rebuilt from its components since an extended path is used exactly as
+//	given, without / being turned into \ or . and .. being resolved.
+//	Relative paths, paths that already have the prefix and all paths on
+//	other platforms are returned unchanged.
+
+#[cfg(not(windows))]
+pub fn extended_path(path: &Path) -> PathBuf {
+
+	path.to_path_buf()
+}
+
+#[cfg(windows)]
+pub fn extended_path(path: &Path) -> PathBuf {
+
+	let mut components = path.components();
+
+	let mut extended = match components.next() {
+		Some(Component::Prefix(prefix)) => match prefix.kind() {
+			Prefix::Disk(drive) => PathBuf::from(format!("\\\\?\\{}:\\", drive as char)),
+			Prefix::UNC(server, share) => {
+				let mut unc = PathBuf::from("\\\\?\\UNC\\");
+				unc.push(server);
+				unc.push(share);
+				unc
+			},
+			_ => return path.to_path_buf(),
+		},
+		_ => return path.to_path_buf(),
+	};
+
+	for component in components {
+		match component {
+			Component::RootDir | Component::CurDir => (),
+			Component::ParentDir => { extended.pop(); },
+			other => extended.push(other.as_os_str()),
+		}
+	}
+
+	extended
+}
+
+//	Remove the extended length prefix added by extended_path, for paths
+//	that are shown to the user or written to the catalog.
+
+pub fn plain_path(path: &Path) -> PathBuf {
+
+	let mut components = path.components();
+
+	let mut plain = match components.next() {
+		Some(Component::Prefix(prefix)) => match prefix.kind() {
+			Prefix::VerbatimDisk(drive) => PathBuf::from(format!("{}:\\", drive as char)),
+			Prefix::VerbatimUNC(server, share) => {
+				let mut unc = std::ffi::OsString::from("\\\\");
+				unc.push(server);
+				unc.push("\\");
+				unc.push(share);
+				unc.push("\\");
+				PathBuf::from(unc)
+			},
+			_ => return path.to_path_buf(),
+		},
+		_ => return path.to_path_buf(),
+	};
+
+	for component in components {
+		if component != Component::RootDir {
+			plain.push(component.as_os_str());
+		}
+	}
+
+	plain
+}
+
+//	The drive a path is on, used to count directories per drive. This is
+//	the drive letter such as C: or the share \\server\share on Windows, and
+//	the first directory under / on Unix.
+
+pub fn drive_id(path: &Path) -> String {
+
+	let mut components = path.components();
+
+	match components.next() {
+		Some(Component::Prefix(prefix)) => match prefix.kind() {
+			Prefix::Disk(drive) | Prefix::VerbatimDisk(drive) => format!("{}:", (drive as char).to_ascii_uppercase()),
+			Prefix::UNC(server, share) | Prefix::VerbatimUNC(server, share) =>
+				format!("\\\\{}\\{}", server.to_string_lossy(), share.to_string_lossy()),
+			_ => prefix.as_os_str().to_string_lossy().to_string(),
+		},
+		Some(Component::RootDir) => match components.next() {
+			Some(Component::Normal(name)) => format!("/{}", name.to_string_lossy()),
+			_ => String::from("/"),
+		},
+		_ => String::new(),
+	}
+}
+
+//	Remove the partial copy suffix from a path, if it has one. Done on the
+//	raw name so names that are not valid UTF-8 are handled.
+
+pub fn strip_partial_suffix(path: &Path) -> Option<PathBuf> {
+
+	let name = path.file_name()?;
+
+	#[cfg(unix)]
+	{
+		use std::os::unix::ffi::OsStrExt;
+		let stripped = name.as_bytes().strip_suffix(PARTIAL_SUFFIX.as_bytes())?;
+		Some(path.with_file_name(std::ffi::OsStr::from_bytes(stripped)))
+	}
+
+	#[cfg(windows)]
+	{
+		let stripped = name.to_str()?.strip_suffix(PARTIAL_SUFFIX)?;
+		Some(path.with_file_name(stripped))
+	}
+}
+
+//	Find the orphans for one source directory. These are the files in its
+//	backup whose source file no longer exists. A partial copy left by the
+//	copy engine is an orphan only if the file it belongs to is. Returns the
+//	orphans with their sizes, and the total number of files in the backup.
+//
+//	Function parameters:
+//
+//	target_base - the backup base location
+//	source_root - the source directory whose backup we examine
+//	skipped - source directories that are not available; their backups may
+//	          lie under source_root's and are left alone
+//	names - name lookup for the source, which decides whether case matters
+//	report - the run report, for any errors found on the way
+
+pub fn find_orphans(target_base: &str,
+					source_root: &Path,
+					skipped: &[PathBuf],
+					names: &mut NameIndex,
+					report: &mut RunReport) -> (Vec<(PathBuf, u64)>, usize) {
+
+	let mut orphans = Vec::<(PathBuf, u64)>::new();
+	let mut file_count: usize = 0;
+	let target_root = build_target_path(target_base, source_root);
+	let skipped_targets = skipped_backups(target_base, skipped);
+	let case_mode = names.case_mode();
+
+	if !target_root.is_dir() {
+		return (orphans, file_count);
+	}
+
+	let walker = walkdir::WalkDir::new(&target_root).follow_links(false).into_iter()
+		.filter_entry(|e| !in_skipped_backup(e.path(), &skipped_targets, case_mode));
+
+	for entry in walker {
+
+		let entry = match entry {
+			Ok(entry) => entry,
+			Err(err) => {
+				report.record_walk_failure(&err);
+				continue;
+			}
+		};
+
+		if !entry.file_type().is_file() && !entry.path_is_symlink() {
+			continue;
+		}
+
+		file_count += 1;
+
+		let backup_file = strip_partial_suffix(entry.path())
+			.unwrap_or_else(|| entry.path().to_path_buf());
+
+		match source_path_for(target_base, &backup_file) {
+			Some(source) => {
+				if names.lookup(&source).is_none() {
+					let size = entry.metadata().map(|m| m.len()).unwrap_or(0);
+					orphans.push((entry.path().to_path_buf(), size));
+				}
+			},
+			None => warn!("Unable to map {:?} back to a source path", entry.path()),
+		}
+	}
+
+	(orphans, file_count)
+}
+
+//	Find the directories in the backup of a source root that should go once
+//	orphaned files have been removed. These are the ones whose source
+//	directory no longer exists, and if keep_empty is false any others as
+//	well, since they are only removed if they are empty. They are returned
+//	deepest first so that removing them in order works from the bottom up.
+//	The backup of the source root itself is never returned, nor is anything
+//	in the backup of a skipped source directory.
+
+pub fn find_orphan_dirs(target_base: &str,
+						source_root: &Path,
+						skipped: &[PathBuf],
+						keep_empty: bool,
+						names: &mut NameIndex,
+						report: &mut RunReport) -> Vec<PathBuf> {
+
+	let mut dirs = Vec::<PathBuf>::new();
+	let target_root = build_target_path(target_base, source_root);
+	let skipped_targets = skipped_backups(target_base, skipped);
+	let case_mode = names.case_mode();
+
+	if !target_root.is_dir() {
+		return dirs;
+	}
+
+//	filter_entry cannot prune a walk that yields contents first, so the walk
+//	is made parents first and the list reversed at the end.
+
+	let walker = walkdir::WalkDir::new(&target_root).min_depth(1).follow_links(false).into_iter()
+		.filter_entry(|e| !in_skipped_backup(e.path(), &skipped_targets, case_mode));
+
+	for entry in walker {
+
+		let entry = match entry {
+			Ok(entry) => entry,
+			Err(err) => {
+				report.record_walk_failure(&err);
+				continue;
+			}
+		};
+
+		if !entry.file_type().is_dir() {
+			continue;
+		}
+
+		if !keep_empty {
+			dirs.push(entry.path().to_path_buf());
+			continue;
+		}
+
+		match source_path_for(target_base, entry.path()) {
+			Some(source) => {
+				if names.lookup(&source).is_none() {
+					dirs.push(entry.path().to_path_buf());
+				}
+			},
+			None => warn!("Unable to map {:?} back to a source path", entry.path()),
+		}
+	}
+
+	dirs.reverse();
+	dirs
+}
+
+//	The backups of the source directories that are not available.
+
+fn skipped_backups(target_base: &str, skipped: &[PathBuf]) -> Vec<PathBuf> {
+
+	skipped.iter().map(|root| build_target_path(target_base, root)).collect()
+}
+
+//	Is a backup path in the backup of a source directory that is not
+//	available? Reconciliation must not touch it, since its source files
+//	would all look missing.
+
+fn in_skipped_backup(path: &Path, skipped_targets: &[PathBuf], case_mode: CaseMode) -> bool {
+
+	skipped_targets.iter().any(|skipped| path_starts_with(path, skipped, case_mode))
+}
+
+//	Create the directory a target file goes in if it is not there, such as
+//	when the directory could not be created up front or has gone since.
+
+pub fn ensure_parent(target: &Path) -> io::Result<()> {
+
+	match target.parent() {
+		Some(parent) if !parent.is_dir() => fs::create_dir_all(parent),
+		_ => Ok(()),
+	}
+}
+
+//	Check that a source directory is available. It must exist, be a
+//	directory and be readable, and the volume or mount it lives on must be
+//	present. Returns the reason if it is not available.
+//
+//	Function parameters:
+//
+//	root - the source directory
+
+pub fn check_source_root(root: &Path) -> Result<(), String> {
+
+	check_source_volume(root)?;
+
+	let meta = match fs::metadata(root) {
+		Ok(meta) => meta,
+		Err(err) => {
+			return Err(match err.kind() {
+				io::ErrorKind::NotFound => String::from("does not exist"),
+				_ => format!("cannot be examined: {}", err),
+			});
+		}
+	};
+
+	if !meta.is_dir() {
+		return Err(String::from("is not a directory"));
+	}
+
+	if let Err(err) = fs::read_dir(root) {
+		return Err(format!("cannot be read: {}", err));
+	}
+
+	Ok(())
+}
+
+//	On Windows the drive (or UNC share) the source is on must be present.
+
+#[cfg(windows)]
+fn check_source_volume(root: &Path) -> Result<(), String> {
+
+	let volume: PathBuf = root.components()
+		.take_while(|c| matches!(c, Component::Prefix(_) | Component::RootDir))
+		.collect();
+
+	if volume.as_os_str().is_empty() {
+		return Err(String::from("is not an absolute path"));
+	}
+
+	if !volume.exists() {
+		return Err(format!("volume {} is not available", volume.display()));
+	}
+
+	Ok(())
+}
+
+//	On Unix a source under one of the usual removable media directories must
+//	be on a mounted file system. If the mount is missing the directory is
+//	just the empty mount point on the root file system, which would look to
+//	reconciliation like every file had been deleted.
+
+#[cfg(unix)]
+fn check_source_volume(root: &Path) -> Result<(), String> {
+
+	if !root.is_absolute() {
+		return Err(String::from("is not an absolute path"));
+	}
+
+	let mounts = fs::read_to_string("/proc/mounts").unwrap_or_default();
+	let mount_point = mounts.lines()
+		.filter_map(|line| line.split_whitespace().nth(1))
+		.map(|m| PathBuf::from(m.replace("\\040", " ")))
+		.filter(|m| root.starts_with(m))
+		.max_by_key(|m| m.components().count());
+
+	let removable = ["/mnt", "/media", "/run/media", "/Volumes"].iter()
+		.any(|base| root.starts_with(base));
+
+	match mount_point {
+		Some(mount_point) if removable && mount_point == Path::new("/") =>
+			Err(String::from("is not on a mounted volume")),
+		_ => Ok(()),
+	}
+}
+
+//	Volume identity. Drive letters and mount points are not tied to a
+//	particular disk, so E: today may be a different USB disk from E:
+//	yesterday. The first run records the identity of every source volume
+//	and of the target volume in the backup location, and later runs compare
+//	against it.
+//
+//	The record is a text file with one volume per line:
+//
+//	<volume>	<identity>
+//
+//	where volume is the drive or mount point, or "target" for the backup
+//	location itself.
+
+pub const VOLUME_FILE_NAME: &str = "sfbprp.volumes";
+pub const TARGET_VOLUME: &str = "target";
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum VolumeCheck {
+	Off,
+	Warn,
+	Refuse,
+}
+
+//	Result of comparing a volume against the record.
+
+#[derive(PartialEq, Debug)]
+pub enum VolumeStatus {
+	New,
+	Same,
+	Changed(String),
+}
+
+//	Get the volume a path is on and its identity. On Windows the volume is
+//	the drive or UNC share and the identity is the volume serial number.
+
+#[cfg(windows)]
+pub fn volume_identity(path: &Path) -> Option<(String, String)> {
+
+	use std::os::windows::ffi::OsStrExt;
+
+	let volume: PathBuf = plain_path(path).components()
+		.take_while(|c| matches!(c, Component::Prefix(_) | Component::RootDir))
+		.collect();
+
+	if volume.as_os_str().is_empty() {
+		return None;
+	}
+
+	let mut root: Vec<u16> = volume.as_os_str().encode_wide().collect();
+	root.push(0);
+	let mut serial: u32 = 0;
+
+	let rc = unsafe {
+		winapi::um::fileapi::GetVolumeInformationW(root.as_ptr(),
+			std::ptr::null_mut(), 0,
+			&mut serial,
+			std::ptr::null_mut(),
+			std::ptr::null_mut(),
+			std::ptr::null_mut(), 0)
+	};
+
+	if rc == 0 {
+		return None;
+	}
+
+	Some((volume.display().to_string(), format!("SERIAL={:04X}-{:04X}", serial >> 16, serial & 0xFFFF)))
+}
+
+//	On Unix the volume is the mount point and the identity is the file
+//	system UUID if the device has one, otherwise the device number.
+
+#[cfg(unix)]
+pub fn volume_identity(path: &Path) -> Option<(String, String)> {
+
+	use std::os::unix::fs::MetadataExt;
+
+	let meta = fs::metadata(path).ok()?;
+	let mounts = fs::read_to_string("/proc/mounts").unwrap_or_default();
+	let (device, mount_point) = mounts.lines()
+		.filter_map(|line| {
+			let mut fields = line.split_whitespace();
+			let device = fields.next()?;
+			let mount_point = fields.next()?;
+			Some((device.replace("\\040", " "), PathBuf::from(mount_point.replace("\\040", " "))))
+		})
+		.filter(|(_, m)| path.starts_with(m))
+		.max_by_key(|(_, m)| m.components().count())
+		.unwrap_or((String::new(), PathBuf::from("/")));
+
+	let volume = mount_point.display().to_string();
+
+	if device.starts_with("/dev/") {
+		if let (Ok(device), Ok(entries)) = (fs::canonicalize(&device), fs::read_dir("/dev/disk/by-uuid")) {
+			for entry in entries.flatten() {
+				if fs::canonicalize(entry.path()).map(|p| p == device).unwrap_or(false) {
+					return Some((volume, format!("UUID={}", entry.file_name().to_string_lossy())));
+				}
+			}
+		}
+	}
+
+	Some((volume, format!("DEV={:X}", meta.dev())))
+}
+
+//	Read the volume record from the backup location. A missing record is
+//	simply empty, as it is on the first run.
+
+pub fn read_volume_record(target_base: &str) -> Vec<(String, String)> {
+
+	let mut record: Vec<(String, String)> = Vec::new();
+	let contents = fs::read_to_string(Path::new(target_base).join(VOLUME_FILE_NAME)).unwrap_or_default();
+
+	for line in contents.lines() {
+		let fields: Vec<&str> = line.splitn(2, '\t').collect();
+		if fields.len() == 2 {
+			record.push((fields[0].to_string(), fields[1].to_string()));
+		}
+	}
+
+	record
+}
+
+//	Write the volume record back to the backup location.
+
+pub fn write_volume_record(target_base: &str, record: &[(String, String)]) -> io::Result<()> {
+
+	let mut contents = String::new();
+
+	for (volume, identity) in record {
+		contents.push_str(&format!("{}\t{}\n", volume, identity));
+	}
+
+	fs::write(Path::new(target_base).join(VOLUME_FILE_NAME), contents)
+}
+
+//	Compare a volume against the record. A volume that is not in the record
+//	is added to it, as is a changed one when accept is set.
+//
+//	Function parameters:
+//
+//	record - the volume record
+//	volume - the drive, mount point or TARGET_VOLUME
+//	identity - the current identity of the volume
+//	accept - replace a changed identity in the record
+
+pub fn check_volume(record: &mut Vec<(String, String)>,
+					volume: &str,
+					identity: &str,
+					accept: bool) -> VolumeStatus {
+
+	let entry = record.iter_mut().find(|(v, _)| v.eq_ignore_ascii_case(volume));
+
+	match entry {
+		None => {
+			record.push((volume.to_string(), identity.to_string()));
+			VolumeStatus::New
+		},
+		Some((_, recorded)) if recorded == identity => VolumeStatus::Same,
+		Some((_, recorded)) => {
+			let previous = recorded.clone();
+			if accept {
+				*recorded = identity.to_string();
+			}
+			VolumeStatus::Changed(previous)
+		},
+	}
+}
+
+//	Symbolic links and junctions. By default links are skipped. They can
+//	instead be copied as links, pointing at the same place as the source
+//	link, or followed so that what they point at is backed up. Following
+//	is done by walkdir, which reports a loop instead of walking it.
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LinkPolicy {
+	Skip,
+	Copy,
+	Follow,
+}
+
+//	Check if a path is a symbolic link, or on Windows a directory junction
+//	or other directory reparse point. Files that are reparse points, such
+//	as cloud placeholders, are treated as ordinary files.
+
+#[cfg(windows)]
+pub fn is_link(path: &Path) -> bool {
+
+	match fs::symlink_metadata(path) {
+		Ok(meta) => meta.file_type().is_symlink() ||
+			(meta.is_dir() && meta.file_attributes() & FILE_ATTRIBUTE_REPARSE_POINT != 0),
+		Err(_) => false,
+	}
+}
+
+#[cfg(not(windows))]
+pub fn is_link(path: &Path) -> bool {
+
+	match fs::symlink_metadata(path) {
+		Ok(meta) => meta.file_type().is_symlink(),
+		Err(_) => false,
+	}
+}
+
+//	Copy a link as a link. Returns true if the target link was created or
+//	replaced, false if it already pointed at the same place.
+
+pub fn copy_link(source: &Path, target: &Path) -> io::Result<bool> {
+
+	let mut link_to = fs::read_link(source)?;
+
+//	Junctions read back with the NT \??\ prefix, which is not usable as
+//	a link target.
+
+	if let Some(rest) = link_to.to_str().and_then(|s| s.strip_prefix("\\??\\")) {
+		link_to = PathBuf::from(rest);
+	}
+
+	if let Ok(existing) = fs::read_link(target) {
+		if existing == link_to {
+			return Ok(false);
+		}
+	}
+
+	if fs::symlink_metadata(target).is_ok() {
+		remove_backup_entry(target)?;
+	}
+
+	create_link(&link_to, source, target)?;
+	Ok(true)
+}
+
+#[cfg(unix)]
+fn create_link(link_to: &Path, _source: &Path, target: &Path) -> io::Result<()> {
+	std::os::unix::fs::symlink(link_to, target)
+}
+
+#[cfg(windows)]
+fn create_link(link_to: &Path, source: &Path, target: &Path) -> io::Result<()> {
+	if source.is_dir() {
+		std::os::windows::fs::symlink_dir(link_to, target)
+	}
+	else {
+		std::os::windows::fs::symlink_file(link_to, target)
+	}
+}
+
+//	Remove a file or link from the backup. A link is removed itself, never
+//	what it points at. On Windows a link to a directory has to be removed
+//	as a directory.
+
+pub fn remove_backup_entry(path: &Path) -> io::Result<()> {
+
+	match fs::remove_file(path) {
+		Ok(()) => Ok(()),
+		Err(err) => {
+			if is_link(path) {
+				fs::remove_dir(path)
+			}
+			else {
+				Err(err)
+			}
+		}
+	}
+}
+
+//	Check that a path really is inside the backup location, with no link
+//	in the directories leading to it taking it somewhere else. Only the
+//	parent is resolved so a link can itself be checked and then removed.
+
+pub fn inside_backup(target_base: &str, path: &Path) -> bool {
+
+	let base = match fs::canonicalize(target_base) {
+		Ok(base) => base,
+		Err(_) => return false,
+	};
+
+	match path.parent().map(fs::canonicalize) {
+		Some(Ok(parent)) => parent.starts_with(&base),
+		_ => false,
+	}
+}
+
+//	Attribute filters. Entries can be left out of the backup for their
+//	attributes. On Unix a hidden entry is one whose name starts with a dot,
+//	special files are sockets, FIFOs and devices, and the system, temporary
+//	and offline filters do not apply.
+//
+//	skip_temporary - files marked temporary
+//	skip_offline - files whose data is not on the disk, such as OneDrive
+//	               placeholders, which would be downloaded by copying them
+//	skip_hidden - hidden files and directories
+//	skip_system - system files and directories
+//	skip_special - devices, sockets and FIFOs, which cannot be copied
+
+pub struct AttributeFilter {
+	pub skip_temporary: bool,
+	pub skip_offline: bool,
+	pub skip_hidden: bool,
+	pub skip_system: bool,
+	pub skip_special: bool,
+}
+
+impl Default for AttributeFilter {
+
+	fn default() -> Self {
+		AttributeFilter {
+			skip_temporary: true,
+			skip_offline: true,
+			skip_hidden: false,
+			skip_system: false,
+			skip_special: true,
+		}
+	}
+}
+
+//	Check a walked entry against the attribute filters. Returns the reason
+//	it is to be skipped, or None to keep it.
+
+#[cfg(windows)]
+pub fn attribute_skip(entry: &walkdir::DirEntry, filter: &AttributeFilter) -> Option<&'static str> {
+
+	let attrib = match entry.metadata() {
+		Ok(meta) => meta.file_attributes(),
+		Err(_) => return None,
+	};
+
+	if filter.skip_temporary && attrib & FILE_ATTRIBUTE_TEMPORARY != 0 {
+		return Some("temporary");
+	}
+
+	if filter.skip_offline && attrib & (FILE_ATTRIBUTE_OFFLINE |
+										FILE_ATTRIBUTE_RECALL_ON_OPEN |
+										FILE_ATTRIBUTE_RECALL_ON_DATA_ACCESS) != 0 {
+		return Some("offline");
+	}
+
+	if filter.skip_hidden && attrib & FILE_ATTRIBUTE_HIDDEN != 0 {
+		return Some("hidden");
+	}
+
+	if filter.skip_system && attrib & FILE_ATTRIBUTE_SYSTEM != 0 {
+		return Some("system");
+	}
+
+	if filter.skip_special && attrib & FILE_ATTRIBUTE_DEVICE != 0 {
+		return Some("special");
+	}
+
+	None
+}
+
+#[cfg(unix)]
+pub fn attribute_skip(entry: &walkdir::DirEntry, filter: &AttributeFilter) -> Option<&'static str> {
+
+	use std::os::unix::fs::FileTypeExt;
+
+	if filter.skip_hidden && entry.file_name().to_string_lossy().starts_with('.') {
+		return Some("hidden");
+	}
+
+	let file_type = entry.file_type();
+
+	if filter.skip_special && (file_type.is_fifo() || file_type.is_socket() ||
+							   file_type.is_block_device() || file_type.is_char_device()) {
+		return Some("special");
+	}
+
+	None
+}
+
+//	Move detection. When a source file or folder is moved, the new location
+//	turns up as new files in the copy plan and the old one as orphans in the
+//	backup. A new file is taken to be a moved orphan if they have the same
+//	size and last write time, since the copy engine gives the backup copy
+//	the last write time of the source. Where more than one orphan matches,
+//	the one with the same file name is used, and if there is none the file
+//	is copied as usual. With verify the contents are compared as well.
+//
+//	Function parameters:
+//
+//	plan - the copy plan
+//	target - index of the backup location the orphans are in
+//	orphans - orphaned backup files and their sizes
+//	min_size - smaller files are left in the plan
+//	verify - compare the contents of the source and the orphan
+//	case_mode - whether a file name that differs only in case is the same
+//
+//	Returns pairs of the index of the plan entry and the orphan to rename.
+
+pub fn find_moves(plan: &[CopyItem],
+				  target: usize,
+				  orphans: &[(PathBuf, u64)],
+				  min_size: u64,
+				  verify: bool,
+				  case_mode: CaseMode) -> Vec<(usize, PathBuf)> {
+
+	let mut candidates: std::collections::HashMap<(u64, std::time::SystemTime), Vec<PathBuf>> =
+		std::collections::HashMap::new();
+
+	for (orphan, size) in orphans {
+		if *size < min_size || strip_partial_suffix(orphan).is_some() {
+			continue;
+		}
+		if let Ok(modified) = fs::symlink_metadata(orphan).and_then(|m| m.modified()) {
+			candidates.entry((*size, modified)).or_default().push(orphan.to_path_buf());
+		}
+	}
+
+	let mut moves = Vec::<(usize, PathBuf)>::new();
+
+	if candidates.is_empty() {
+		return moves;
+	}
+
+	for (x, item) in plan.iter().enumerate() {
+
+		let copy_target = match item.targets.iter().find(|t| t.index == target) {
+			Some(copy_target) => copy_target,
+			None => continue,
+		};
+
+		if item.size < min_size || fs::symlink_metadata(&copy_target.path).is_ok() {
+			continue;
+		}
+
+		let modified = match fs::metadata(&item.source).and_then(|m| m.modified()) {
+			Ok(modified) => modified,
+			Err(_) => continue,
+		};
+
+		let matches = match candidates.get_mut(&(item.size, modified)) {
+			Some(matches) => matches,
+			None => continue,
+		};
+
+		let name = item.source.file_name().map(|n| name_key(n, case_mode));
+		let same_name = matches.iter().position(|m| m.file_name().map(|n| name_key(n, case_mode)) == name);
+
+		let chosen = match same_name {
+			Some(pos) if !verify || files_match(&item.source, &matches[pos]).unwrap_or(false) => Some(pos),
+			Some(_) => None,
+			None if verify => matches.iter().position(|m| files_match(&item.source, m).unwrap_or(false)),
+			None if matches.len() == 1 => Some(0),
+			None => None,
+		};
+
+		if let Some(pos) = chosen {
+			moves.push((x, matches.remove(pos)));
+		}
+	}
+
+	moves
+}
+
+//	Compare the contents of two files.
+
+pub fn files_match(first: &Path, second: &Path) -> io::Result<bool> {
+
+	let mut first = BufReader::with_capacity(COPY_CHUNK, File::open(first)?);
+	let mut second = BufReader::with_capacity(COPY_CHUNK, File::open(second)?);
+
+	loop {
+		let first_buf = first.fill_buf()?;
+		let second_buf = second.fill_buf()?;
+		let n = first_buf.len().min(second_buf.len());
+
+		if n == 0 {
+			return Ok(first_buf.is_empty() && second_buf.is_empty());
+		}
+
+		if first_buf[..n] != second_buf[..n] {
+			return Ok(false);
+		}
+
+		first.consume(n);
+		second.consume(n);
+	}
+}
+
+//	Case handling. NTFS and most Windows file systems ignore case in names
+//	while most Unix file systems do not, and a backup may well go from one
+//	to the other. CaseMode says whether names that differ only in case are
+//	the same name. It is used when matching exclusions, when deciding if a
+//	backup file still has a source file, and when matching moved files.
+//	Auto takes the usual behaviour of the platform we are running on.
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CaseMode {
+	Auto,
+	Sensitive,
+	Insensitive,
+}
+
+impl CaseMode {
+
+	pub fn insensitive(self) -> bool {
+		match self {
+			CaseMode::Auto => cfg!(any(windows, target_os = "macos")),
+			CaseMode::Sensitive => false,
+			CaseMode::Insensitive => true,
+		}
+	}
+}
+
+//	The form of a name used to compare it under the case mode. A name that
+//	is not valid UTF-8 is kept exactly as it is, since a lossy conversion
+//	would give two different names the same key.
+
+pub fn name_key(name: &std::ffi::OsStr, case_mode: CaseMode) -> std::ffi::OsString {
+
+	match name.to_str() {
+		Some(text) if case_mode.insensitive() => std::ffi::OsString::from(text.to_lowercase()),
+		_ => name.to_os_string(),
+	}
+}
+
+//	Find out whether the file system at a location ignores case in names. A
+//	probe file is created under a lower case name and looked for under the
+//	upper case one.
+//
+//	Function parameters:
+//
+//	dir - the directory to probe
+
+pub fn ignores_case(dir: &Path) -> io::Result<bool> {
+
+	let probe = dir.join(format!("sfbprp-case-{}.tmp", process::id()));
+	let upper = dir.join(format!("SFBPRP-CASE-{}.TMP", process::id()));
+
+	File::create(&probe)?;
+	let found = fs::symlink_metadata(&upper).is_ok();
+	let _ = fs::remove_file(&probe);
+
+	Ok(found)
+}
+
+//	Path::starts_with, comparing each component under the case mode.
+
+pub fn path_starts_with(path: &Path, prefix: &Path, case_mode: CaseMode) -> bool {
+
+	if !case_mode.insensitive() {
+		return path.starts_with(prefix);
+	}
+
+	let mut path_components = path.components();
+
+	for prefix_component in prefix.components() {
+		match path_components.next() {
+			Some(component) => {
+				if name_key(component.as_os_str(), case_mode) != name_key(prefix_component.as_os_str(), case_mode) {
+					return false;
+				}
+			},
+			None => return false,
+		}
+	}
+
+	true
+}
+
+//	The source roots whose backups are walked when reconciling. A root that
+//	lies inside another root in the list is left out, since the walk of the
+//	outer root's backup already covers its files, and a root listed twice
+//	is only walked once. Otherwise those files would be counted twice
+//	against the deletion limits and removed twice.
+//
+//	Function parameters:
+//
+//	roots - the available source roots
+//	case_mode - decides whether case matters when comparing the roots
+
+pub fn outermost_roots(roots: &[PathBuf], case_mode: CaseMode) -> Vec<PathBuf> {
+
+	let mut outermost = Vec::<PathBuf>::new();
+
+	for (x, root) in roots.iter().enumerate() {
+		let covered = roots.iter().enumerate().any(|(y, other)| {
+			y != x && path_starts_with(root, other, case_mode) &&
+				(y < x || !path_starts_with(other, root, case_mode))
+		});
+		if !covered {
+			outermost.push(root.clone());
+		}
+	}
+
+	outermost
+}
+
+//	Sort out the source roots for reconciliation. Returns the roots that are
+//	not available, because they were skipped or have gone since, whose
+//	backups are left alone; and the outermost of the others, whose backups
+//	are walked for orphans.
+//
+//	Function parameters:
+//
+//	roots - the source roots of the job
+//	skipped - the source roots skipped when they were checked
+//	case_mode - decides whether case matters when comparing the roots
+
+pub fn reconcile_roots(roots: &[PathBuf], skipped: &[PathBuf], case_mode: CaseMode) -> (Vec<PathBuf>, Vec<PathBuf>) {
+
+	let (unavailable, available): (Vec<PathBuf>, Vec<PathBuf>) = roots.iter()
+		.cloned()
+		.partition(|root| skipped.contains(root) || !root.is_dir());
+
+	let walk_roots = outermost_roots(&available, case_mode);
+
+	(unavailable, walk_roots)
+}
+
+//	Looks up names in directories under the case mode, so that whether a
+//	file exists does not depend on the file system it is on. Each directory
+//	is read once and its names kept.
+
+pub struct NameIndex {
+	case_mode: CaseMode,
+	dirs: std::collections::HashMap<PathBuf, std::collections::HashMap<std::ffi::OsString, std::ffi::OsString>>,
+}
+
+impl NameIndex {
+
+	pub fn new(case_mode: CaseMode) -> NameIndex {
+		NameIndex {
+			case_mode,
+			dirs: std::collections::HashMap::new(),
+		}
+	}
+
+	//	Find the entry in the directory of path with the same name under the
+	//	case mode, and return its path with the name as it is on disk.
+
+	pub fn lookup(&mut self, path: &Path) -> Option<PathBuf> {
+
+		let parent = path.parent()?;
+		let name = name_key(path.file_name()?, self.case_mode);
+		let case_mode = self.case_mode;
+
+		let names = self.dirs.entry(parent.to_path_buf()).or_insert_with(|| {
+			let mut names = std::collections::HashMap::new();
+			if let Ok(entries) = fs::read_dir(parent) {
+				for entry in entries.flatten() {
+					names.insert(name_key(&entry.file_name(), case_mode), entry.file_name());
+				}
+			}
+			names
+		});
+
+		names.get(&name).map(|actual| parent.join(actual))
+	}
+
+	pub fn case_mode(&self) -> CaseMode {
+		self.case_mode
+	}
+
+	//	Drop what we know about a directory after changing it.
+
+	pub fn forget(&mut self, dir: &Path) {
+		self.dirs.remove(dir);
+	}
+}
+
+//	Bring the case of a backup file or directory name into line with its
+//	source after a case-only rename. Only needed when case does not matter,
+//	since otherwise the old name is just an orphan. The rename is done in
+//	two steps because some file systems ignore a rename that only changes
+//	case. Returns true if the name was changed.
+//
+//	Function parameters:
+//
+//	target - the backup path with the case of the source name
+//	names - name lookup for the backup
+
+pub fn fix_name_case(target: &Path, names: &mut NameIndex) -> io::Result<bool> {
+
+	let actual = match names.lookup(target) {
+		Some(actual) => actual,
+		None => return Ok(false),
+	};
+
+	if actual.file_name() == target.file_name() {
+		return Ok(false);
+	}
+
+	let mut temp = target.as_os_str().to_os_string();
+	temp.push(".sfbcase");
+
+	fs::rename(&actual, &temp)?;
+	fs::rename(&temp, target)?;
+
+	if let Some(parent) = target.parent() {
+		names.forget(parent);
+	}
+
+	Ok(true)
+}
+
+//	Backup jobs. The parameter file can hold more than one job, each with
+//	its own sources, excludes, target and options:
+//
+//	RetryCount = 5
+//	Job = Documents
+//	BackupSource = ...
+//	BackupBaseLocation = ...
+//	Job = Photos
+//	...
+//
+//	Anything before the first Job line applies to every job, and a job can
+//	override it. BackupBaseLocation can be given more than once to copy to
+//	several locations; a job that gives it replaces the shared ones. A file
+//	without any Job lines is a single job with no name.
+//	Logging keywords are not part of a job since there is one log per run.
+//
+//	Source and Exclude lines list directories in the parameter file itself,
+//	instead of or as well as the list files. The sources of a job are taken
+//	as a whole: a job that gives BackupSource or Source replaces both the
+//	shared BackupSource and the shared Source lines, and ExcludeSource and
+//	Exclude likewise replace both shared exclude settings. Paths can hold
+//	environment variables such as %USERPROFILE% or $HOME.
+//
+//	name - name of the job, empty for the unnamed job
+//	bkup_source - file listing the source directories
+//	exclude_source - file listing the directories to exclude
+//	sources - source directories given with Source lines
+//	excludes - directories to exclude given with Exclude lines
+//	targets - the backup base locations; a job can copy to more than one
+//	parms - the other keyword = value pairs, shared ones first
+
+#[derive(Clone, Default)]
+pub struct JobConfig {
+	pub name: String,
+	pub bkup_source: String,
+	pub exclude_source: String,
+	pub sources: Vec<String>,
+	pub excludes: Vec<String>,
+	pub targets: Vec<String>,
+	pub parms: Vec<(String, String)>,
+}
+
+//	Read the jobs from the parameter file. Comment lines start with # and
+//	blank lines are ignored.
+
+pub fn read_job_config(parm_file: &str) -> io::Result<Vec<JobConfig>> {
+
+	let fh = File::open(parm_file)?;
+	let mut shared = JobConfig::default();
+	let mut jobs = Vec::<JobConfig>::new();
+	let mut own_targets = false;
+
+//	The shared section has nothing to inherit, so its source and exclude
+//	settings are its own from the start.
+
+	let mut own_sources = true;
+	let mut own_excludes = true;
+
+	for line in BufReader::new(fh).lines() {
+
+		let line = line?;
+		let line = line.trim();
+
+		if line.is_empty() || line.starts_with('#') {
+			continue;
+		}
+
+		let parts: Vec<&str> = line.splitn(2, '=').collect();
+		let key = parts[0].trim();
+		let value = if parts.len() == 2 { parts[1].trim() } else { "" };
+
+		if key == "Job" {
+			let mut job = shared.clone();
+			job.name = value.to_string();
+			jobs.push(job);
+			own_targets = false;
+			own_sources = false;
+			own_excludes = false;
+			continue;
+		}
+
+		let job = match jobs.last_mut() {
+			Some(job) => job,
+			None => &mut shared,
+		};
+
+		if (key == "BackupSource" || key == "Source") && !own_sources {
+			job.bkup_source.clear();
+			job.sources.clear();
+			own_sources = true;
+		}
+
+		if (key == "ExcludeSource" || key == "Exclude") && !own_excludes {
+			job.exclude_source.clear();
+			job.excludes.clear();
+			own_excludes = true;
+		}
+
+		match key {
+			"BackupSource" => job.bkup_source = expand_env(value),
+			"ExcludeSource" => job.exclude_source = expand_env(value),
+			"BackupBaseLocation" => add_to_list(&mut job.targets, &mut own_targets, value),
+			"Source" => job.sources.push(expand_env(value)),
+			"Exclude" => job.excludes.push(expand_env(value)),
+			_ if key.starts_with("Log") => (),
+			_ => job.parms.push((key.to_string(), value.to_string())),
+		}
+	}
+
+	if jobs.is_empty() {
+		jobs.push(shared);
+	}
+
+//	Each job writes its reports under its name, so two jobs whose names are
+//	the same once made safe for a file name, such as My Photos and
+//	My_Photos, would overwrite each other's reports. Case is ignored since
+//	--job= picks jobs without regard to case.
+
+	for (x, job) in jobs.iter().enumerate() {
+		let key = safe_job_name(&job.name).to_lowercase();
+		if let Some(other) = jobs[..x].iter().find(|o| safe_job_name(&o.name).to_lowercase() == key) {
+			let message = if other.name.eq_ignore_ascii_case(&job.name) {
+				format!("Job {} is given more than once in {}", job.name, parm_file)
+			}
+			else {
+				format!("Jobs {} and {} in {} would write the same reports, give them names that differ in more than punctuation",
+						other.name, job.name, parm_file)
+			};
+			return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+		}
+	}
+
+	Ok(jobs)
+}
+
+//	Add a backup location to the list. The first one given in a job
+//	replaces any inherited from the shared section.
+
+fn add_to_list(list: &mut Vec<String>, own: &mut bool, value: &str) {
+
+	if !*own {
+		list.clear();
+		*own = true;
+	}
+
+	list.push(expand_env(value));
+}
+
+//	Read a source or exclude list file, one directory per line. Blank lines
+//	and lines starting with # are skipped, so the lists can be commented,
+//	and environment variables in the paths are expanded. The file is read
+//	as bytes so that one line that is not valid UTF-8 does not stop the
+//	job; on Unix such a line is used as the path exactly as it is, and on
+//	Windows the characters that cannot be read are replaced.
+
+pub fn read_path_list(list_file: &str) -> io::Result<Vec<PathBuf>> {
+
+	let contents = fs::read(list_file)?;
+	let mut paths = Vec::<PathBuf>::new();
+
+	for line in contents.split(|&b| b == b'\n') {
+
+		let line = line.trim_ascii();
+
+		if line.is_empty() || line.starts_with(b"#") {
+			continue;
+		}
+
+		match std::str::from_utf8(line) {
+			Ok(text) => paths.push(PathBuf::from(expand_env(text))),
+			Err(_) => {
+				let lossy = String::from_utf8_lossy(line);
+				warn!("{} is not valid UTF-8 in {}, environment variables are not expanded", lossy, list_file);
+				#[cfg(unix)]
+				{
+					use std::os::unix::ffi::OsStrExt;
+					paths.push(PathBuf::from(std::ffi::OsStr::from_bytes(line)));
+				}
+				#[cfg(windows)]
+				paths.push(PathBuf::from(lossy.to_string()));
+			}
+		}
+	}
+
+	Ok(paths)
+}
+
+//	Expand environment variables in a path. Windows style %NAME% and Unix
+//	style $NAME or ${NAME} are both understood. A variable that is not set
+//	is left as written, so the path shows up as missing rather than quietly
+//	pointing somewhere else.
+
+pub fn expand_env(text: &str) -> String {
+
+	let mut expanded = String::with_capacity(text.len());
+	let mut rest = text;
+
+	while let Some(pos) = rest.find(['%', '$']) {
+
+		expanded.push_str(&rest[..pos]);
+		let marker = &rest[pos..];
+
+		let (name, len) = if let Some(after) = marker.strip_prefix('%') {
+			match after.find('%') {
+				Some(end) if end > 0 => (&after[..end], end + 2),
+				_ => ("", 1),
+			}
+		}
+		else if let Some(after) = marker.strip_prefix("${") {
+			match after.find('}') {
+				Some(end) if end > 0 => (&after[..end], end + 3),
+				_ => ("", 1),
+			}
+		}
+		else {
+			let after = &marker[1..];
+			let end = after.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+				.unwrap_or(after.len());
+			(&after[..end], end + 1)
+		};
+
+		if name.is_empty() {
+			expanded.push_str(&marker[..len]);
+		}
+		else {
+			match env::var(name) {
+				Ok(value) => expanded.push_str(&value),
+				Err(_) => {
+					warn!("Environment variable {} is not set in {}", name, text);
+					expanded.push_str(&marker[..len]);
+				}
+			}
+		}
+
+		rest = &marker[len..];
+	}
+
+	expanded.push_str(rest);
+	expanded
+}
+
+//	The name the reports of a job are written under. The reports of the
+//	unnamed job go next to the log file as before; those of a named job
+//	have the job name added, so Log_2021-04-27.txt gives
+//	Log_2021-04-27.Photos.json and Log_2021-04-27.Photos.html.
+
+pub fn job_report_name(lfn: &Path, job: &str) -> PathBuf {
+
+	if job.is_empty() {
+		return lfn.to_path_buf();
+	}
+
+	let stem = lfn.file_stem().unwrap_or_default().to_string_lossy();
+
+	lfn.with_file_name(format!("{}.{}.txt", stem, safe_job_name(job)))
+}
+
+//	A job name with anything that is not a letter, digit, - or _ replaced
+//	by _, so that it can be used in a file name.
+
+fn safe_job_name(job: &str) -> String {
+
+	job.chars()
+		.map(|c| if c.is_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+		.collect()
+}
+
+//	A backup location of a job while it runs.
+//
+//	base - the backup base location
+//	usable - false once the location has been given up on, for example
+//	         because it is locked by another run
+//	copying - false once no more files are to be copied to it, such as when
+//	          it is full; it can still be reconciled
+//	rc - return code for the location
+//	lock - the run lock held in the location
+//	names - name lookup for the location, used when case does not matter
+//	catalog - the catalog of the location
+//	plan_growth - bytes the copy plan writes to the location
+//	orphans - files in the backup whose source file no longer exists
+//	reconcile_aborted - true if the orphans were over the deletion limits,
+//	                    so nothing is to be moved or removed
+//	stats - figures for the run report
+
+pub struct BackupTarget {
+	pub base: String,
+	pub usable: bool,
+	pub copying: bool,
+	pub rc: i32,
+	pub lock: Option<RunLock>,
+	pub names: NameIndex,
+	pub catalog: Option<File>,
+	pub plan_growth: u64,
+	pub orphans: Vec<(PathBuf, u64)>,
+	pub reconcile_aborted: bool,
+	pub stats: TargetStats,
+}
+
+impl BackupTarget {
+
+	pub fn new(base: &str, case_mode: CaseMode) -> BackupTarget {
+		BackupTarget {
+			base: base.to_string(),
+			usable: true,
+			copying: true,
+			rc: 0,
+			lock: None,
+			names: NameIndex::new(case_mode),
+			catalog: None,
+			plan_growth: 0,
+			orphans: Vec::new(),
+			reconcile_aborted: false,
+			stats: TargetStats {
+				target_base: base.to_string(),
+				..Default::default()
+			},
+		}
+	}
+
+	//	Give up on the location for the rest of the job.
+
+	pub fn skip(&mut self, rc: i32, reason: &str) {
+		self.usable = false;
+		self.copying = false;
+		self.rc = self.rc.max(rc);
+		self.stats.skipped = reason.to_string();
+		self.lock = None;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+
+	use super::*;
+
+	//	A backup path must map back to the source it was copied from, or
+	//	reconciliation would treat the copy as an orphan and remove it.
+
+	#[cfg(windows)]
+	#[test]
+	fn target_path_round_trip_drive() {
+		let source = Path::new(r"C:\Users\bill\Documents\x.txt");
+		let target = build_target_path(r"D:\Backup", source);
+		assert_eq!(target, PathBuf::from(r"\\?\D:\Backup\C\Users\bill\Documents\x.txt"));
+		assert_eq!(source_path_for(r"D:\Backup", &target), Some(extended_path(source)));
+	}
+
+	#[cfg(windows)]
+	#[test]
+	fn target_path_round_trip_unc() {
+		let source = Path::new(r"\\server\share\dir\x.txt");
+		let target = build_target_path(r"D:\Backup", source);
+		assert_eq!(target, PathBuf::from(r"\\?\D:\Backup\UNC\server\share\dir\x.txt"));
+		assert_eq!(source_path_for(r"D:\Backup", &target), Some(extended_path(source)));
+	}
+
+	#[cfg(windows)]
+	#[test]
+	fn target_path_round_trip_unpaired_surrogate() {
+		use std::os::windows::ffi::OsStringExt;
+		let name = std::ffi::OsString::from_wide(&[0xD800, 'x' as u16]);
+		let source = Path::new(r"C:\dir").join(&name);
+		let target = build_target_path(r"D:\Backup", &source);
+		assert_eq!(target.file_name(), Some(name.as_os_str()));
+		assert_eq!(source_path_for(r"D:\Backup", &target), Some(extended_path(&source)));
+	}
+
+	#[cfg(windows)]
+	#[test]
+	fn target_path_outside_base() {
+		assert_eq!(source_path_for(r"D:\Backup", Path::new(r"D:\Other\C\x.txt")), None);
+	}
+
+	#[cfg(windows)]
+	#[test]
+	fn drive_id_windows() {
+		assert_eq!(drive_id(Path::new(r"c:\Users\bill")), "C:");
+		assert_eq!(drive_id(Path::new(r"\\?\C:\Users\bill")), "C:");
+		assert_eq!(drive_id(Path::new(r"\\server\share\dir")), r"\\server\share");
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn target_path_round_trip_unix() {
+		let source = Path::new("/home/bill/x.txt");
+		let target = build_target_path("/backup", source);
+		assert_eq!(target, PathBuf::from("/backup/home/bill/x.txt"));
+		assert_eq!(source_path_for("/backup", &target), Some(source.to_path_buf()));
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn target_path_round_trip_non_utf8() {
+		use std::os::unix::ffi::OsStrExt;
+		let name = std::ffi::OsStr::from_bytes(b"\xff\xfe.txt");
+		let source = Path::new("/home/bill").join(name);
+		let target = build_target_path("/backup", &source);
+		assert_eq!(target.file_name(), Some(name));
+		assert_eq!(source_path_for("/backup", &target), Some(source));
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn target_path_outside_base() {
+		assert_eq!(source_path_for("/backup", Path::new("/other/home/x.txt")), None);
+	}
+
+	//	Paths in the lists are often Windows paths, so a lone % or a $ that
+	//	does not start a variable name must be left as it is.
+
+	#[test]
+	fn expand_env_leaves_literals() {
+		assert_eq!(expand_env("100%% done"), "100%% done");
+		assert_eq!(expand_env(r"C:\50%\x"), r"C:\50%\x");
+		assert_eq!(expand_env(r"C:\${}\x"), r"C:\${}\x");
+		assert_eq!(expand_env("/home/bill$"), "/home/bill$");
+		assert_eq!(expand_env(r"\\server\C$\x"), r"\\server\C$\x");
+	}
+
+	#[test]
+	fn expand_env_leaves_unset() {
+		env::remove_var("SFBPRP_TEST_UNSET");
+		assert_eq!(expand_env(r"%SFBPRP_TEST_UNSET%\x"), r"%SFBPRP_TEST_UNSET%\x");
+		assert_eq!(expand_env("$SFBPRP_TEST_UNSET/x"), "$SFBPRP_TEST_UNSET/x");
+		assert_eq!(expand_env("${SFBPRP_TEST_UNSET}/x"), "${SFBPRP_TEST_UNSET}/x");
+		assert_eq!(expand_env(r"C:\$Recycle.Bin"), r"C:\$Recycle.Bin");
+	}
+
+	#[test]
+	fn expand_env_expands_set() {
+		env::set_var("SFBPRP_TEST_DIR", "Users");
+		assert_eq!(expand_env(r"C:\%SFBPRP_TEST_DIR%\x"), r"C:\Users\x");
+		assert_eq!(expand_env("/$SFBPRP_TEST_DIR/x"), "/Users/x");
+		assert_eq!(expand_env("/${SFBPRP_TEST_DIR}x"), "/Usersx");
+	}
+
+	#[test]
+	fn name_key_folds_case() {
+		let key = name_key(std::ffi::OsStr::new("Photos.JPG"), CaseMode::Insensitive);
+		assert_eq!(key, std::ffi::OsString::from("photos.jpg"));
+		let key = name_key(std::ffi::OsStr::new("Photos.JPG"), CaseMode::Sensitive);
+		assert_eq!(key, std::ffi::OsString::from("Photos.JPG"));
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn name_key_keeps_non_utf8() {
+		use std::os::unix::ffi::OsStrExt;
+		let first = std::ffi::OsStr::from_bytes(b"A\xff.txt");
+		let second = std::ffi::OsStr::from_bytes(b"A\xfe.txt");
+		assert_eq!(name_key(first, CaseMode::Insensitive), first.to_os_string());
+		assert_ne!(name_key(first, CaseMode::Insensitive), name_key(second, CaseMode::Insensitive));
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn drive_id_unix() {
+		assert_eq!(drive_id(Path::new("/home/bill")), "/home");
+		assert_eq!(drive_id(Path::new("/")), "/");
+	}
+
+	//	Catalog records are one line of four tab separated fields, so the
+	//	characters that would break a record must come back unchanged.
+
+	#[test]
+	fn catalog_escape_round_trip() {
+		for field in ["C:\\plain\\x.txt", "a\tb", "line\nbreak\r", "100%", "%09", "%2509", "%%0A%"] {
+			let escaped = catalog_escape(field);
+			assert!(!escaped.contains('\t') && !escaped.contains('\n') && !escaped.contains('\r'));
+			assert_eq!(catalog_unescape(&escaped), field);
+		}
+	}
+
+	#[test]
+	fn catalog_query_parses() {
+		let tokens: Vec<String> = ["path=C:\\Users\\*", "Name=*.jpg", "minsize=10", "MAXSIZE=20",
+								   "from=2021-01-01", "to=2021-12-31"]
+			.iter().map(|t| t.to_string()).collect();
+		let mut query = CatalogQuery::default();
+		assert!(parse_catalog_query(&tokens, &mut query));
+		assert_eq!(query.path_glob.as_deref(), Some("C:\\Users\\*"));
+		assert_eq!(query.name_glob.as_deref(), Some("*.jpg"));
+		assert_eq!(query.min_size, Some(10));
+		assert_eq!(query.max_size, Some(20));
+		assert_eq!(query.from_date.as_deref(), Some("2021-01-01"));
+		assert_eq!(query.to_date.as_deref(), Some("2021-12-31"));
+	}
+
+	#[test]
+	fn catalog_query_rejects() {
+		for token in ["path", "size=10", "minsize=ten", "maxsize=-1"] {
+			let mut query = CatalogQuery::default();
+			assert!(!parse_catalog_query(&[token.to_string()], &mut query), "{}", token);
+		}
+	}
+
+	#[test]
+	fn catalog_query_matches() {
+		let entry = CatalogEntry {
+			run_time: String::from("2021-04-27 10:00:00"),
+			size: 15,
+			source: String::from("C:/Users/bill/IMG_001.JPG"),
+			target: String::from("D:\\Backup\\C\\Users\\bill\\IMG_001.JPG"),
+		};
+		let mut query = CatalogQuery::default();
+		assert!(catalog_match(&entry, &query));
+		query.name_glob = Some(String::from("img_???.jpg"));
+		query.min_size = Some(15);
+		query.max_size = Some(15);
+		query.from_date = Some(String::from("2021-04-27"));
+		query.to_date = Some(String::from("2021-04-27"));
+		assert!(catalog_match(&entry, &query));
+		query.to_date = Some(String::from("2021-04-26"));
+		assert!(!catalog_match(&entry, &query));
+	}
+
+	#[test]
+	fn wildcard_match_stars() {
+		assert!(wildcard_match("*", ""));
+		assert!(wildcard_match("*", "anything"));
+		assert!(wildcard_match("**", "x"));
+		assert!(wildcard_match("*.txt", ".txt"));
+		assert!(wildcard_match("a*b*c", "aXbYbZc"));
+		assert!(wildcard_match("*ab", "aab"));
+		assert!(!wildcard_match("*.txt", "x.txt.bak"));
+		assert!(!wildcard_match("a*b", "acd"));
+		assert!(!wildcard_match("", "x"));
+		assert!(wildcard_match("", ""));
+	}
+
+	#[test]
+	fn wildcard_match_question() {
+		assert!(wildcard_match("?", "x"));
+		assert!(!wildcard_match("?", ""));
+		assert!(!wildcard_match("?", "xy"));
+		assert!(wildcard_match("x?z", "xyz"));
+		assert!(wildcard_match("*?", "x"));
+		assert!(!wildcard_match("?*?", "x"));
+		assert!(wildcard_match("FILE?.TXT", "file1.txt"));
+	}
+
+	//	Pruning removes whatever log_name_matches accepts, so it must only
+	//	accept names construct_lf_name could have made.
+
+	#[test]
+	fn log_name_parts_default_pattern() {
+		let (prefix, suffix) = log_name_parts(LOG_FILE_PATTERN, "sfbprp");
+		assert_eq!(prefix, "Log_");
+		assert_eq!(suffix, ".txt");
+		let (prefix, suffix) = log_name_parts("logs\\{job}_{date}_run.log", "Photos");
+		assert_eq!(prefix, "Photos_");
+		assert_eq!(suffix, "_run.log");
+	}
+
+	#[test]
+	fn log_name_matches_logs() {
+		let (prefix, suffix) = log_name_parts(LOG_FILE_PATTERN, "sfbprp");
+		assert!(log_name_matches("Log_20210427-101010.txt", &prefix, &suffix));
+		assert!(log_name_matches("Log_20210427-101010-1.txt", &prefix, &suffix));
+		assert!(log_name_matches("Log_20210427-101010-12.txt", &prefix, &suffix));
+		let (prefix, suffix) = log_name_parts("{job}_{date}_run.log", "Photos");
+		assert!(log_name_matches("Photos_20210427-101010_run.log", &prefix, &suffix));
+		assert!(log_name_matches("Photos_20210427-101010_run-3.log", &prefix, &suffix));
+	}
+
+	#[test]
+	fn log_name_matches_rejects() {
+		let (prefix, suffix) = log_name_parts(LOG_FILE_PATTERN, "sfbprp");
+		for name in ["Log_20210427-101010.json", "Log_20210427-101010.html",
+					 "Log_20210427-101010.Photos.txt", "Log_20210427-101010.Photos.json",
+					 "Log_20210427-101010-.txt", "Log_20210427-101010-x.txt",
+					 "Log_2021042-1010101.txt", "Log_notes.txt", "notes.txt", "Log_.txt"] {
+			assert!(!log_name_matches(name, &prefix, &suffix), "{}", name);
+		}
+		let (prefix, suffix) = log_name_parts("{date}.txt", "sfbprp");
+		assert!(prefix.is_empty());
+		assert!(!log_name_matches("readme.txt", &prefix, &suffix));
+		assert!(!log_name_matches("20210427-101010.json", &prefix, &suffix));
+	}
+
+	#[test]
+	fn parse_size_suffixes() {
+		let mut size: u64 = 0;
+		assert!(parse_size("512", &mut size) && size == 512);
+		assert!(parse_size("4K", &mut size) && size == 4096);
+		assert!(parse_size("4kb", &mut size) && size == 4096);
+		assert!(parse_size(" 10 MB ", &mut size) && size == 10 * 1024 * 1024);
+		assert!(parse_size("2G", &mut size) && size == 2 * 1024 * 1024 * 1024);
+	}
+
+	//	A value that does not parse must leave the setting as it was.
+
+	#[test]
+	fn parse_size_rejects() {
+		let mut size: u64 = 7;
+		for value in ["", "K", "10T", "10X", "1.5G", "-1", "ten", "10KK"] {
+			assert!(!parse_size(value, &mut size), "{}", value);
+			assert_eq!(size, 7);
+		}
+	}
+
+	#[test]
+	fn parse_size_overflow() {
+		let mut size: u64 = 7;
+		assert!(!parse_size("99999999999G", &mut size));
+		assert!(!parse_size("18446744073709551615K", &mut size));
+		assert!(!parse_size("18446744073709551616", &mut size));
+		assert_eq!(size, 7);
+		assert!(parse_size("18446744073709551615", &mut size));
+		assert_eq!(size, u64::MAX);
+	}
+
+	#[test]
+	fn outermost_roots_drops_nested() {
+		let roots: Vec<PathBuf> = ["/home/bill", "/home/bill/Photos", "/srv", "/home/bill", "/home/billy"]
+			.iter().map(PathBuf::from).collect();
+		let walk = outermost_roots(&roots, CaseMode::Sensitive);
+		assert_eq!(walk, vec![PathBuf::from("/home/bill"), PathBuf::from("/srv"), PathBuf::from("/home/billy")]);
+		let roots: Vec<PathBuf> = ["/Home/Bill/photos", "/home/bill"].iter().map(PathBuf::from).collect();
+		assert_eq!(outermost_roots(&roots, CaseMode::Insensitive), vec![PathBuf::from("/home/bill")]);
+		assert_eq!(outermost_roots(&roots, CaseMode::Sensitive).len(), 2);
+	}
+
+	//	Set up a source file to be copied and orphans that all have its size
+	//	and last write time, as a move would leave them.
+
+	fn move_fixture(test: &str, orphan_names: &[&str]) -> (PathBuf, Vec<CopyItem>, Vec<(PathBuf, u64)>) {
+		let dir = env::temp_dir().join(format!("sfbprp-{}-{}", test, process::id()));
+		let _ = fs::remove_dir_all(&dir);
+		fs::create_dir_all(dir.join("source")).unwrap();
+		fs::create_dir_all(dir.join("backup")).unwrap();
+		let modified = filetime::FileTime::from_unix_time(1_600_000_000, 0);
+		let source = dir.join("source").join("IMG_001.JPG");
+		fs::write(&source, b"photo").unwrap();
+		filetime::set_file_mtime(&source, modified).unwrap();
+		let mut orphans = Vec::<(PathBuf, u64)>::new();
+		for name in orphan_names {
+			let orphan = dir.join("backup").join(name);
+			fs::write(&orphan, b"photo").unwrap();
+			filetime::set_file_mtime(&orphan, modified).unwrap();
+			orphans.push((orphan, 5));
+		}
+		let plan = vec![CopyItem {
+			source,
+			size: 5,
+			targets: vec![CopyTarget { index: 0, path: dir.join("backup").join("new.jpg"), readonly: false }],
+		}];
+		(dir, plan, orphans)
+	}
+
+	#[test]
+	fn find_moves_prefers_same_name() {
+		let (dir, plan, orphans) = move_fixture("same-name", &["IMG_002.JPG", "IMG_001.JPG", "IMG_003.JPG"]);
+		let moves = find_moves(&plan, 0, &orphans, 0, false, CaseMode::Sensitive);
+		assert_eq!(moves, vec![(0, dir.join("backup").join("IMG_001.JPG"))]);
+		let moves = find_moves(&plan, 0, &orphans, 0, true, CaseMode::Sensitive);
+		assert_eq!(moves, vec![(0, dir.join("backup").join("IMG_001.JPG"))]);
+		let _ = fs::remove_dir_all(&dir);
+	}
+
+	#[test]
+	fn find_moves_copies_unnamed_tie() {
+		let (dir, plan, orphans) = move_fixture("tie", &["IMG_002.JPG", "IMG_003.JPG"]);
+		assert!(find_moves(&plan, 0, &orphans, 0, false, CaseMode::Sensitive).is_empty());
+		let _ = fs::remove_dir_all(&dir);
+		let (dir, plan, orphans) = move_fixture("single", &["IMG_002.JPG"]);
+		assert_eq!(find_moves(&plan, 0, &orphans, 0, false, CaseMode::Sensitive),
+				   vec![(0, dir.join("backup").join("IMG_002.JPG"))]);
+		assert!(find_moves(&plan, 0, &orphans, 6, false, CaseMode::Sensitive).is_empty());
+		let _ = fs::remove_dir_all(&dir);
+	}
+
+	fn report_with_sources(roots: &[&str]) -> RunReport {
+		let mut report = RunReport::default();
+		for root in roots {
+			report.sources.push(SourceStats { root: root.to_string(), ..Default::default() });
+		}
+		report
+	}
+
+	//	The copy engine works with extended paths while the report holds the
+	//	plain form of each source root.
+
+	#[cfg(windows)]
+	#[test]
+	fn record_copy_extended_path() {
+		let mut report = report_with_sources(&[r"C:\Users\bill", r"\\server\share\docs"]);
+		report.record_copy(&extended_path(Path::new(r"C:\Users\bill\x.txt")), 10);
+		report.record_copy(Path::new(r"\\?\UNC\server\share\docs\y.txt"), 20);
+		report.record_copy(Path::new(r"\\?\C:\Users\billy\z.txt"), 40);
+		assert_eq!((report.sources[0].files_copied, report.sources[0].bytes_copied), (1, 10));
+		assert_eq!((report.sources[1].files_copied, report.sources[1].bytes_copied), (1, 20));
+		assert_eq!(report.largest_files[0], (String::from(r"C:\Users\billy\z.txt"), 40));
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn record_copy_counts_source() {
+		let mut report = report_with_sources(&["/home/bill", "/srv"]);
+		report.record_copy(&extended_path(Path::new("/home/bill/x.txt")), 10);
+		report.record_copy(Path::new("/srv/a/y.txt"), 20);
+		report.record_copy(Path::new("/home/billy/z.txt"), 40);
+		assert_eq!((report.sources[0].files_copied, report.sources[0].bytes_copied), (1, 10));
+		assert_eq!((report.sources[1].files_copied, report.sources[1].bytes_copied), (1, 20));
+		assert_eq!(report.largest_files.len(), 3);
+	}
 }
@@ -12,25 +12,86 @@
 
 //	Bring in code we need.
 
-use log::info;
+use log::{debug, error, info, warn};
+use std::env;
 use std::fs;
-use std::fs::File;
-use std::io::{BufRead, BufReader};
-use std::path::PathBuf;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::process;
-use std::time::{Duration, Instant};
+use std::time::Instant;
 
 //	Bring in other crates.
 
 use walkdir::WalkDir;
+use fs2::available_space;
 
 //	Get some local functions from lib.rs
 
-use sfbp::house_keeping;
-use sfbp::construct_lf_name;
-use sfbp::setup_logger;
-use sfbp::get_meta;
-use sfbp::make_file_writable;
+use sfbprp::house_keeping;
+use sfbprp::construct_lf_name;
+use sfbprp::setup_logger;
+use sfbprp::read_log_parms;
+use sfbprp::prune_log_files;
+use sfbprp::read_job_config;
+use sfbprp::read_path_list;
+use sfbprp::job_report_name;
+use sfbprp::JobConfig;
+use sfbprp::LogConfig;
+use sfbprp::get_meta;
+use sfbprp::make_file_writable;
+use sfbprp::open_catalog;
+use sfbprp::catalog_record;
+use sfbprp::parse_catalog_query;
+use sfbprp::search_catalog;
+use sfbprp::CatalogQuery;
+use sfbprp::write_json_report;
+use sfbprp::write_html_report;
+use sfbprp::RunReport;
+use sfbprp::SourceStats;
+use sfbprp::RunParms;
+use sfbprp::copy_with_retry;
+use sfbprp::classify_io_error;
+use sfbprp::is_transient;
+use sfbprp::FailureKind;
+use sfbprp::CopyItem;
+use sfbprp::CopyTarget;
+use sfbprp::BackupTarget;
+use sfbprp::Progress;
+use sfbprp::Throttle;
+use sfbprp::CancelCheck;
+use sfbprp::acquire_run_lock;
+use sfbprp::LockError;
+use sfbprp::build_target_path;
+use sfbprp::extended_path;
+use sfbprp::plain_path;
+use sfbprp::drive_id;
+use sfbprp::find_orphans;
+use sfbprp::check_source_root;
+use sfbprp::LinkPolicy;
+use sfbprp::is_link;
+use sfbprp::copy_link;
+use sfbprp::remove_backup_entry;
+use sfbprp::inside_backup;
+use sfbprp::find_orphan_dirs;
+use sfbprp::ensure_parent;
+use sfbprp::find_moves;
+use sfbprp::path_starts_with;
+use sfbprp::reconcile_roots;
+use sfbprp::fix_name_case;
+use sfbprp::NameIndex;
+use sfbprp::CaseMode;
+use sfbprp::ignores_case;
+use sfbprp::attribute_skip;
+use sfbprp::FILE_ATTRIBUTE_READONLY;
+use sfbprp::FILE_ATTRIBUTE_DIRECTORY;
+use sfbprp::format_bytes;
+use sfbprp::volume_identity;
+use sfbprp::read_volume_record;
+use sfbprp::write_volume_record;
+use sfbprp::check_volume;
+use sfbprp::VolumeCheck;
+use sfbprp::VolumeStatus;
+use sfbprp::TARGET_VOLUME;
 
 // Define some constants
 
@@ -40,28 +101,7 @@ const GIGA_BYTE: f64 = MEGA_BYTE * KILO_BYTE;
 const NUMB_PARM: u16 = 2;
 const DEBUG_FLAG: bool = false;
 const RC00: i32 = 0;
-
-//	Windows file system constants. We only make use of two of them
-//	but have them all listed for documentation purposes.
-
-const FILE_ATTRIBUTE_READONLY: u32 =            0x00000001;
-const FILE_ATTRIBUTE_HIDDEN: u32 =              0x00000002;
-const FILE_ATTRIBUTE_SYSTEM: u32 =              0x00000004;
-const FILE_ATTRIBUTE_DIRECTORY: u32 =           0x00000010;
-const FILE_ATTRIBUTE_ARCHIVE: u32 =             0x00000020;
-const FILE_ATTRIBUTE_DEVICE: u32 =              0x00000040;
-const FILE_ATTRIBUTE_NORMAL: u32 =              0x00000080;
-const FILE_ATTRIBUTE_TEMPORARY: u32 =           0x00000100;
-const FILE_ATTRIBUTE_SPARSE_FILE: u32 =         0x00000200;
-const FILE_ATTRIBUTE_REPARSE_POINT: u32 =       0x00000400;
-const FILE_ATTRIBUTE_COMPRESSED: u32 =          0x00000800;
-const FILE_ATTRIBUTE_OFFLINE: u32 =             0x00001000;
-const FILE_ATTRIBUTE_NOT_CONTENT_INDEXED: u32 = 0x00002000;
-const FILE_ATTRIBUTE_ENCRYPTED: u32 =           0x00004000;
-const FILE_ATTRIBUTE_INTEGRITY_STREAM: u32 =    0x00008000;
-const FILE_ATTRIBUTE_VIRTUAL: u32 =             0x00010000;
-const FILE_ATTRIBUTE_NO_SCRUB_DATA: u32 =       0x00020000;
-const FILE_ATTRIBUTE_EA: u32 =                  0x00040000;
+const RC12: i32 = 12;
 
 //	Executable code starts here.
 
@@ -69,12 +109,255 @@ fn main() {
 
 	info!("Beginning program execution");
 	
-	let mut log_file_name = String::with_capacity(255);
-	let log_file_prefix = String::from("\\Log_");
+	let mut log_file_name = PathBuf::new();
 	let mut prog_name = String::with_capacity(25);
-	let mut _bkup_source = String::new();
-	let mut _exclude_source = String::new();
-	let mut _target_base = String::new();
+
+//	Do some simple housekeeping using house_keeping from lib.rs
+
+	house_keeping(NUMB_PARM,&mut prog_name);
+
+//	Anything following the log location is either an option, which starts
+//	with --, or an optional command. At present the only commands are find
+//	and history, which search the catalog.
+
+	let cli_args: Vec<String> = env::args().collect();
+	let cli_opts: Vec<String> = cli_args.iter().skip(NUMB_PARM as usize)
+		.filter(|a| a.starts_with("--")).cloned().collect();
+	let cli_words: Vec<String> = cli_args.iter().skip(NUMB_PARM as usize)
+		.filter(|a| !a.starts_with("--")).cloned().collect();
+	let mut _run_command = String::new();
+	
+	if !cli_words.is_empty() {
+		_run_command = cli_words[0].to_lowercase();
+		if _run_command != "find" && _run_command != "history" {
+			println!("Unknown command {}", cli_words[0]);
+			process::exit(16);
+		}
+	}
+
+//	Check every option is one we know, so that a mistyped option such as
+//	--no-reconcle stops the run rather than being ignored.
+
+	for opt in &cli_opts {
+		let name = opt.split('=').next().unwrap_or("");
+		if !LogConfig::OPTIONS.contains(&name) && !RunParms::OPTIONS.contains(&name) && name != "--job" {
+			println!("Unknown option {}", opt);
+			process::exit(16);
+		}
+	}
+
+//	Work out how logging should be set up. The defaults can be overridden
+//	by Log keywords in the parameter file, and those by --log options.
+
+	let mut log_config = LogConfig {
+		echo: DEBUG_FLAG,
+		..LogConfig::default()
+	};
+	if !read_log_parms(&format!("{}.parms", prog_name), &mut log_config) {
+		process::exit(16);
+	}
+	
+	if !log_config.apply_args(&cli_opts) {
+		process::exit(16);
+	}
+
+//	Build the log file name using construct_lf_name from lib.rs
+
+	if let Err(err) = construct_lf_name(&mut log_file_name,&log_config.file_pattern,&prog_name) {
+		println!("Unable to create log file {:?} {}", log_file_name, err);
+		process::exit(16);
+	}
+
+//	Create the log file using setup_logger from lib.rs
+
+	setup_logger(&log_file_name,&log_config)
+		.expect("failed to initialize logging.");
+	
+	prune_log_files(&log_file_name, &prog_name, &log_config);
+
+//	Log file has been opened so we can proceed.
+
+	info!("Beginning program execution");
+	
+//	Read the backup jobs from our parameter file. A parameter file without
+//	any Job lines is a single job, as it always was. Jobs can be picked by
+//	name with --job=name, or --job=name,name for more than one; otherwise
+//	every job is run.
+
+	let parm_file = format!("{}.parms", prog_name);
+	info!("Attempting to open {}",parm_file);
+	
+	let mut jobs = match read_job_config(&parm_file) {
+		Ok(jobs) => jobs,
+		Err(err) => {
+			info!("{}", err);
+			info!("Terminating program execution");
+			process::exit(16);
+		}
+	};
+	
+	let selected: Vec<String> = cli_opts.iter()
+		.filter_map(|opt| opt.strip_prefix("--job="))
+		.flat_map(|names| names.split(','))
+		.map(|name| name.trim().to_string())
+		.collect();
+	
+	if !selected.is_empty() {
+		for name in &selected {
+			if !jobs.iter().any(|job| job.name.eq_ignore_ascii_case(name)) {
+				error!("There is no job named {} in {}", name, parm_file);
+				info!("Terminating program execution");
+				process::exit(16);
+			}
+		}
+		jobs.retain(|job| selected.iter().any(|name| job.name.eq_ignore_ascii_case(name)));
+	}
+
+//	Work out the options for each job. Each starts from the defaults in
+//	lib.rs, then the parameter file, then options given on the command line.
+//	A keyword we do not know, such as a misspelt one, or a value that is not
+//	valid ends the run rather than quietly leaving the default in place,
+//	since the defaults decide what is deleted from the backup.
+
+	let mut job_parms = Vec::<RunParms>::new();
+	
+	for job in &jobs {
+	
+		let mut run_parms = RunParms::default();
+		let mut valid = true;
+		
+		for (key, value) in &job.parms {
+			if !run_parms.apply_parm(key, value) {
+				error!("Unknown keyword or invalid value in {}: {} = {}", parm_file, key, value);
+				valid = false;
+			}
+		}
+		
+		if !valid || !run_parms.apply_args(&cli_opts) {
+			info!("Terminating program execution");
+			process::exit(16);
+		}
+		
+		job_parms.push(run_parms);
+	}
+
+//	If we were asked to find or list the history of files, search the
+//	catalog in every backup location of each job, display what we
+//	found and end. find lists matches in the order they were copied,
+//	history groups the matches by source file so every time a file was
+//	copied is listed together. These are copy events, not versions: each
+//	copy replaces the last one, so only the latest is in the backup.
+
+if !_run_command.is_empty() {
+
+	let mut query = CatalogQuery::default();
+	
+	if !parse_catalog_query(&cli_words[1..], &mut query) {
+		println!("Criteria are path=, name=, minsize=, maxsize=, from= and to=");
+		process::exit(16);
+	}
+	
+	let mut total_found: usize = 0;
+	
+	let multiple = jobs.len() > 1 || jobs.iter().any(|job| job.targets.len() > 1);
+	
+	for job in &jobs {
+	for target_base in &job.targets {
+	
+		if multiple {
+			println!("Job {} - {}", job.name, target_base);
+		}
+		
+		let mut found = search_catalog(target_base, &query);
+		info!("Catalog {} found {} entries in {}", _run_command, found.len(), target_base);
+		
+		if _run_command == "history" {
+			found.sort_by(|a,b| a.source.to_lowercase().cmp(&b.source.to_lowercase())
+				.then(a.run_time.cmp(&b.run_time)));
+		}
+		
+		let mut last_source = String::new();
+		
+		for entry in &found {
+			if _run_command == "history" {
+				if !entry.source.eq_ignore_ascii_case(&last_source) {
+					println!("{}", entry.source);
+					last_source = entry.source.clone();
+				}
+				println!("    {} copied {:>15} bytes to {}", entry.run_time, entry.size, entry.target);
+			}
+			else {
+				println!("{} {:>15} {} => {}", entry.run_time, entry.size, entry.source, entry.target);
+			}
+		}
+		
+		total_found += found.len();
+	}
+	}
+	
+	println!("{} matching entries", total_found);
+	info!("Terminating program execution");
+	process::exit(RC00);
+	
+}
+
+//	Run the jobs one after another, then log a combined summary when there
+//	is more than one. The return code is the highest of any job.
+
+	let mut results = Vec::<(String, i32, RunReport)>::new();
+	
+	for (job, run_parms) in jobs.iter().zip(job_parms.iter()) {
+		if !job.name.is_empty() {
+			info!("Beginning job {}", job.name);
+		}
+		let (rc, report) = run_job(job, run_parms, &prog_name, &log_file_name);
+		results.push((job.name.clone(), rc, report));
+	}
+	
+	if results.len() > 1 {
+	
+		info!("Summary of {} jobs", results.len());
+		
+		for (name, rc, report) in &results {
+			info!("Job {} - return code {}, {} files copied, {}, {} errors",
+				  name, rc, report.files_copied, format_bytes(report.bytes_copied), report.errors.len());
+		}
+		
+		info!("All jobs - {} files copied, {}, {} errors",
+			  results.iter().map(|r| r.2.files_copied).sum::<u64>(),
+			  format_bytes(results.iter().map(|r| r.2.bytes_copied).sum()),
+			  results.iter().map(|r| r.2.errors.len()).sum::<usize>());
+	}
+	
+	let max_rc = results.iter().map(|r| r.1).max().unwrap_or(RC00);
+	
+	info!("Terminating program execution");
+	
+	if max_rc != RC00 {
+		process::exit(max_rc);
+	}
+
+}
+
+//	Run one backup job. Everything from reading the list of source
+//	directories to writing the reports happens here, so that the jobs in
+//	a parameter file can be run one after another. Returns the return code
+//	for the job along with its run report.
+//
+//	Function parameters:
+//
+//	job - the job to run
+//	run_parms - options for the job
+//	prog_name - name of the program we were invoked under
+//	log_file_name - name of the log file, used to name the reports
+
+fn run_job(job: &JobConfig,
+		   run_parms: &RunParms,
+		   prog_name: &str,
+		   log_file_name: &Path) -> (i32, RunReport) {
+
+	let _bkup_source = job.bkup_source.clone();
+	let _exclude_source = job.exclude_source.clone();
 	let mut _copy_message = String::new();
 	
 	let mut bytes_copied_u64: u64 = 0;
@@ -107,119 +390,78 @@ fn main() {
 	
 	let mut _drive_id = Vec::<String>::new();
 	let mut _drive_ct = Vec::<i32>::new();
-
-//	Do some simple housekeeping using house_keeping from lib.rs
-
-	house_keeping(NUMB_PARM,&mut prog_name);
-
-//	Build the log file name using construct_lf_name from lib.rs
-
-	construct_lf_name(&mut log_file_name,&log_file_prefix,&prog_name);
-
-//	Create the log file using setup_logger from lib.rs
-
-	setup_logger(&log_file_name,DEBUG_FLAG)
-		.expect("failed to initialize logging.");
-
-//	Log file has been opened so we can proceed.
-
-	info!("Beginning program execution");
 	
-//	Construct the name of our parameter file, and then create a path to it.
-//	Create a code block so that objects, variable, etc. related to the 
-//	parameter file go away when we are done with them.
-
-{
-
-	let mut parm_file = prog_name.clone();
-	parm_file.push_str(".parms");
-
-	info!("Attempting to open {}",parm_file);
+//	The run report collects the figures we log at the end of the run, any
+//	errors we encounter along the way and per source directory counts.
 
-//	let fh = File::open(&parm_file);
-	
-	let fh = match File::open(&parm_file) {
-		Ok(file) => file,
-		Err(err) => {
-			info!("{}", err);
-			info!("Terminating program execution");
-			process::exit(16);
-		}
+	let mut run_report = RunReport {
+		program: prog_name.to_string(),
+		job: job.name.clone(),
+		run_start: chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+		..RunReport::default()
 	};
 	
-//	If we are here then we have opened up the file.
-//	Next step is to establish a handle to the file,
-//	and then see if we can read the file ignoring any
-//	comments in the file. A comment will start with the
-//	# character.
+	let mut _bkup_roots = Vec::<PathBuf>::new();
+	let mut _skipped_roots = Vec::<PathBuf>::new();
 	
-    let pf_handle = BufReader::new(fh);
-
-    for line in pf_handle.lines() {
-        let line = line.expect("Unable to read line");
-		if &line[..1] != "#" {
-			let bkup_parms:Vec<&str>= line.split("=").collect();			
-			if bkup_parms[0].trim() == "BackupSource" {
-				_bkup_source = bkup_parms[1].trim().to_string();
-			}
-			if bkup_parms[0].trim() == "ExcludeSource" {
-				_exclude_source = bkup_parms[1].trim().to_string();
-			}
-			if bkup_parms[0].trim() == "BackupBaseLocation" {
-				_target_base = bkup_parms[1].trim().to_string();
-			}
-		}
-    }
-
 	info!("Source directory list is {}", _bkup_source);
 	info!("Exclude directory list is {}", _exclude_source);
-	info!("Target backup location is {}", _target_base);
+	info!("Number of sources given in the parameter file is {}", job.sources.len());
+	info!("Number of excludes given in the parameter file is {}", job.excludes.len());
+	for target_base in &job.targets {
+		info!("Target backup location is {}", target_base);
+	}
 	
-	if _bkup_source == "" {
+	if _bkup_source.is_empty() && job.sources.is_empty() {
 		info!("No source directory list provided");
-		process::exit(RC00);
+		return (RC12, run_report);
 	}
 	
-	if _exclude_source == "" {
-		info!("No exclude directory list provided");
-		process::exit(RC00);
+	if _exclude_source.is_empty() && job.excludes.is_empty() {
+		info!("No exclude directory list provided, nothing will be excluded");
 	}
 	
-	if _target_base == "" {
+	if job.targets.is_empty() || job.targets.iter().any(|t| t.is_empty()) {
 		info!("No target directory base provided");
-		process::exit(RC00);
+		return (RC12, run_report);
 	}
 	
-}
+	let mut targets: Vec<BackupTarget> = job.targets.iter()
+		.map(|base| BackupTarget::new(base, run_parms.case_mode))
+		.collect();
 
-//	This code block processes the source directories file.
+//	This code block processes the source directories, those given with
+//	Source lines in the parameter file followed by those in the source
+//	directories file.
 //	We will build the list of directories into _bkup_s1.
 //	We also populate _drive_id and _drive_ct for use later.
 {
 
-	let fh = match File::open(_bkup_source) {
-		Ok(file) => file,
-		Err(err) => {
-			info!("{}", err);
-			info!("Terminating program execution");
-			process::exit(RC00);
+	let mut source_list: Vec<PathBuf> = job.sources.iter().map(PathBuf::from).collect();
+	
+	if !_bkup_source.is_empty() {
+		match read_path_list(&_bkup_source) {
+			Ok(mut paths) => source_list.append(&mut paths),
+			Err(err) => {
+				info!("{} {}", _bkup_source, err);
+				info!("Ending job {}", job.name);
+				return (RC12, run_report);
+			}
 		}
-	};
-
-	let pf_handle = BufReader::new(fh);
+	}
+	
 	let mut source_prefix = String::with_capacity(5);
 	
-	for line in pf_handle.lines() {
+	for line in &source_list {
 	
-		let line = line.expect("Unable to read line");
-		source_prefix = line[0..2].to_string();
+		source_prefix = drive_id(Path::new(&line));
 		
 		if !_drive_id.contains(&source_prefix) {
 			_drive_id.push(source_prefix);
 			_drive_ct.push(0);
 		}
 		
-		_bkup_s1.push(PathBuf::from(&line));
+		_bkup_s1.push(extended_path(Path::new(&line)));
 		
 	}
 	
@@ -236,30 +478,56 @@ fn main() {
 	
 	_drive_id.sort();
 	
+//	Check that every source directory is available before we go near it.
+//	A source that is missing, is not a directory, cannot be read or whose
+//	drive or mount is not there is skipped: it is not backed up and its
+//	backup is left alone by reconciliation.
+
+	for entry in &_bkup_s1 {
+	
+		let mut skipped = String::new();
+		
+		if let Err(reason) = check_source_root(entry) {
+			warn!("Source {:?} skipped: {}", entry, reason);
+			skipped = reason;
+			_skipped_roots.push(entry.to_path_buf());
+		}
+		
+		_bkup_roots.push(entry.to_path_buf());
+		run_report.sources.push(SourceStats {
+			root: plain_path(entry).display().to_string(),
+			skipped,
+			..Default::default()
+		});
+	}
+	
 	let _num_bkup_s1 = _bkup_s1.len();
 	info!("Number of base directories to backup is {}",_num_bkup_s1);
+	info!("Number of base directories skipped is {}",_skipped_roots.len());
 	
 }
 
-//	This code block processes the exclude directories file.
+//	This code block processes the exclude directories, from Exclude lines
+//	in the parameter file and the exclude directories file.
 //	We will build the list of exclude directories into _excl_s1.
 
 {
 
-	let fh = match File::open(_exclude_source) {
-		Ok(file) => file,
-		Err(err) => {
-			info!("{}", err);
-			info!("Terminating program execution");
-			process::exit(RC00);
+	let mut exclude_list: Vec<PathBuf> = job.excludes.iter().map(PathBuf::from).collect();
+	
+	if !_exclude_source.is_empty() {
+		match read_path_list(&_exclude_source) {
+			Ok(mut paths) => exclude_list.append(&mut paths),
+			Err(err) => {
+				info!("{} {}", _exclude_source, err);
+				info!("Ending job {}", job.name);
+				return (RC12, run_report);
+			}
 		}
-	};
-
-	let pf_handle = BufReader::new(fh);
+	}
 	
-	    for line in pf_handle.lines() {
-        let line = line.expect("Unable to read line");
-		_excl_s1.push(PathBuf::from(&line));
+	    for line in &exclude_list {
+		_excl_s1.push(extended_path(Path::new(&line)));
     }
 	
 		let _num_excl_s1 = _excl_s1.len();
@@ -269,29 +537,153 @@ fn main() {
 
 {
 
-//	Following code block obtains the metadata about the provided target
-//	backup directory and validates that it is a directory.
+//	Following code block obtains the metadata about each target backup
+//	directory and validates that it is a directory. A location that is not
+//	is left out of the job and the others carry on.
 
-	let mut _work_path_buf = PathBuf::new();
-	_work_path_buf.push(&_target_base);
+	for target in targets.iter_mut() {
 	
-	get_meta(&_work_path_buf,
-			&mut source_file_attrib,
-			&mut source_creation_time,
-			&mut source_access_time,
-			&mut source_last_write_time,
-			&mut source_filesize);
-			
-	if source_file_attrib & FILE_ATTRIBUTE_DIRECTORY ==
-		FILE_ATTRIBUTE_DIRECTORY {
-		info!("{} validated as a directory structure", _target_base);
+		let mut _work_path_buf = PathBuf::new();
+		_work_path_buf.push(&target.base);
+		
+		source_file_attrib = 0;
+		get_meta(&_work_path_buf,
+				&mut source_file_attrib,
+				&mut source_creation_time,
+				&mut source_access_time,
+				&mut source_last_write_time,
+				&mut source_filesize);
+				
+		if source_file_attrib & FILE_ATTRIBUTE_DIRECTORY ==
+			FILE_ATTRIBUTE_DIRECTORY {
+			info!("{} validated as a directory structure", target.base);
+		}
+		else {
+			info!("{} is not a valid directory structure!", target.base);
+			target.skip(RC12, "not a directory");
+		}
 	}
-	else {
-		info!("{} is not a valid directory structure!", _target_base);
-		info!("Terminating program execution");
-		process::exit(RC00);
+	
+}
+
+//	Take the run lock in each target backup location so that no other run
+//	can work on it at the same time. The locks are released when targets
+//	goes out of scope as run_job returns.
+
+	for target in targets.iter_mut().filter(|t| t.usable) {
+	
+		match acquire_run_lock(&target.base, run_parms.lock_stale_hours) {
+			Ok(lock) => target.lock = Some(lock),
+			Err(LockError::Held(holder)) => {
+				error!("{} is in use by another run: {}", target.base, holder);
+				target.skip(RC12, "in use by another run");
+			},
+			Err(LockError::Io(err)) => {
+				error!("Unable to create run lock in {} {:?}", target.base, err);
+				target.skip(RC12, "unable to create run lock");
+			}
+		}
+	}
+	
+//	With CaseMode Sensitive, Foo.txt and foo.txt are different names. On a
+//	target that ignores case they are the same file, so a source renamed
+//	only in case would have its one backup copy taken for an orphan and
+//	removed. Such a target is refused rather than risk that.
+
+	if run_parms.case_mode == CaseMode::Sensitive {
+		for target in targets.iter_mut().filter(|t| t.usable) {
+			match ignores_case(Path::new(&target.base)) {
+				Ok(false) => (),
+				Ok(true) => {
+					error!("{} ignores case in file names, it cannot be used with CaseMode = Sensitive", target.base);
+					target.skip(RC12, "file system ignores case");
+				},
+				Err(err) => {
+					error!("Unable to find out whether {} ignores case {:?}", target.base, err);
+					target.skip(RC12, "unable to check case handling");
+				}
+			}
+		}
+	}
+	
+	if !targets.iter().any(|t| t.usable) {
+		info!("No backup location is available");
+		info!("Ending job {}", job.name);
+		return finish_job(job, log_file_name, run_report, &targets);
+	}
+
+//	Check that each target and every source are on the same volumes as last
+//	time. A drive letter or mount point can end up on a different disk, and
+//	backing up or reconciling against the wrong one would do real damage.
+//	New volumes are added to the record kept in each target; changed ones
+//	are refused or warned about depending on VolumeCheck, unless
+//	--accept-volumes is given. A refused target is left out of the job, and
+//	a refused source is not backed up to any of them.
+
+if run_parms.volume_check != VolumeCheck::Off {
+
+	let refuse = run_parms.volume_check == VolumeCheck::Refuse && !run_parms.accept_volumes;
+	
+	for target in targets.iter_mut().filter(|t| t.usable) {
+	
+		let mut record = read_volume_record(&target.base);
+		
+		if let Some((_, identity)) = volume_identity(Path::new(&target.base)) {
+			match check_volume(&mut record, TARGET_VOLUME, &identity, run_parms.accept_volumes) {
+				VolumeStatus::New => info!("Recorded target volume identity {}", identity),
+				VolumeStatus::Same => (),
+				VolumeStatus::Changed(previous) => {
+					if refuse {
+						error!("{} is on volume {} but the backup was made on {}", target.base, identity, previous);
+						error!("Use --accept-volumes if this is the intended backup disk");
+						target.skip(RC12, "volume has changed");
+						continue;
+					}
+					warn!("{} is on volume {} but the backup was made on {}", target.base, identity, previous);
+				},
+			}
+		}
+		
+		for (x, entry) in _bkup_s1.iter().enumerate() {
+		
+			if _skipped_roots.contains(entry) {
+				continue;
+			}
+			
+			let (volume, identity) = match volume_identity(entry) {
+				Some(id) => id,
+				None => {
+					warn!("Unable to get the volume identity of {:?}", entry);
+					continue;
+				}
+			};
+			
+			match check_volume(&mut record, &volume, &identity, run_parms.accept_volumes) {
+				VolumeStatus::New => info!("Recorded volume {} identity {}", volume, identity),
+				VolumeStatus::Same => (),
+				VolumeStatus::Changed(previous) => {
+					if refuse {
+						warn!("Source {:?} skipped: volume {} is {} but was {}", entry, volume, identity, previous);
+						_skipped_roots.push(entry.to_path_buf());
+						run_report.sources[x].skipped = format!("volume {} has changed", volume);
+					}
+					else {
+						warn!("Source {:?} volume {} is {} but was {}", entry, volume, identity, previous);
+					}
+				},
+			}
+		}
+		
+		if let Err(err) = write_volume_record(&target.base, &record) {
+			warn!("Unable to write volume record in {} {:?}", target.base, err);
+		}
 	}
 	
+	if !targets.iter().any(|t| t.usable) {
+		info!("No backup location is available");
+		info!("Ending job {}", job.name);
+		return finish_job(job, log_file_name, run_report, &targets);
+	}
 }
 
 //
@@ -301,27 +693,64 @@ fn main() {
 //	_bkup_s1 contains the preliminary list of source directories.
 //	
 
+//	Links are dropped here if LinkPolicy is Skip. With Follow, walkdir
+//	follows them and reports any loop as an error rather than walking it.
+//	Entries are also dropped for their attributes as set by the Skip
+//	parameters; a directory that is dropped takes its contents with it.
+
 {
 	
 	let mut my_count: i32 = 0;
+	let mut links_skipped: usize = 0;
+	let mut attrib_skipped: usize = 0;
+	let follow_links = run_parms.link_policy == LinkPolicy::Follow;
+	
+	for (x, current_source) in _bkup_s1.iter().enumerate() {
 	
-	for current_source in &_bkup_s1 {
-		for entry in WalkDir::new(&current_source).min_depth(0).
-			sort_by(|a,b| a.file_name().cmp(b.file_name())) {
+		if _skipped_roots.contains(current_source) {
+			continue;
+		}
+		
+		let walker = WalkDir::new(current_source).min_depth(0).follow_links(follow_links).
+			sort_by(|a,b| a.file_name().cmp(b.file_name())).into_iter().
+			filter_entry(|e| {
+				if e.depth() == 0 {
+					return true;
+				}
+				match attribute_skip(e, &run_parms.attribute_filter) {
+					Some(reason) => {
+						debug!("Skipping {} entry {:?}", reason, e.path());
+						attrib_skipped += 1;
+						false
+					},
+					None => true,
+				}
+			});
+		
+		for entry in walker {
 				
 				match entry {
 					Ok(entry) => {
-						my_count = my_count + 1;
+						if run_parms.link_policy == LinkPolicy::Skip && is_link(entry.path()) {
+							debug!("Skipping link {:?}", entry.path());
+							links_skipped += 1;
+							continue;
+						}
+						my_count += 1;
+						run_report.sources[x].entries += 1;
 						_bkup_s2.push(entry.path().to_path_buf());
 					} ,
 					Err(entry) => {
-						info!("Error obtaining directory entry {:?}", entry);
+						run_report.record_walk_failure(&entry);
 					}
 				};
 		}
 	}
 	
 	info!("Number of potential backups = {:?}", _bkup_s2.len());
+	info!("Number of links skipped = {}", links_skipped);
+	info!("Number of entries skipped for their attributes = {}", attrib_skipped);
+	run_report.potential_backups = _bkup_s2.len();
 }
 
 //	Following block removes entries from _bkup_s2 that have patterns that are
@@ -332,93 +761,148 @@ fn main() {
 {
 	let mut push_flag: bool = false;
 	let excl_count = _excl_s1.len();
+	let mut excl_hits = vec![0usize; excl_count];
 	
 	for entry in &_bkup_s2 {
 	
+		push_flag = false;
+		
 		for x in 0..excl_count {
-			if entry.starts_with(&_excl_s1[x]) {
+			if path_starts_with(entry, &_excl_s1[x], run_parms.case_mode) {
 				push_flag = true;
+				excl_hits[x] += 1;
 			}
 		}
 		
 		if !push_flag {
 			_bkup_s1.push(entry.to_path_buf());
-			push_flag = false;
 		}
 		
 	}
 	
 	info!("Number of potential backups after removing exclusions = {:?}", _bkup_s1.len());
+	run_report.excluded_entries = _bkup_s2.len() - _bkup_s1.len();
+	
+	for x in 0..excl_count {
+		run_report.exclusions.push((_excl_s1[x].display().to_string(), excl_hits[x]));
+	}
 }
 
 //	The following code block processes the entries in the _bkup_s2 vector.
 //	These are all of the entries that were discovered in the previous block
 //	and are either a directory or a file entry. The purpose of this block
 //	is to take each entry that is a source directory and determine if the
-//	associated target directory exists. To do this, we take a path entry in
-//	_bkup_s2 and test to see if it is a directory. If it is, then we will 
-//	make a string copy of the path, strip out the colon and then prefix
-//	the result with _target_base to create the target path. We test to see if
-//	the target exists, and if it does not we will create it.
+//	associated target directory exists in each backup location. To do this,
+//	we take a path entry in _bkup_s2 and test to see if it is a directory.
+//	If it is, then we will build the target path with build_target_path,
+//	which strips out the colon and prefixes the result with the target base.
+//	We test to see if the target exists, and if it does not we will create
+//	it.
 //
 //	We will use _drive_id[?} to increment the counts in _drive_ct[?].
+//
+//	When case does not matter, a backup directory or file whose name only
+//	differs in case from its source is renamed to match, here and when the
+//	copy plan is built. The names index of each target keeps track of the
+//	names in its backup.
+
+	let mut case_renames: usize = 0;
 
 {
 	
 	let mut my_new_dir: i32 = 0;
-	let mut entry_length: usize = 0;
 	let mut drive_count = _drive_id.len();
 	let mut source_prefix = String::with_capacity(5);
 	let mut final_path = PathBuf::new();
-	let mut path_string = String::with_capacity(100);
 	
 	_bkup_s1.sort();
 		
 	for entry in &_bkup_s1 {
 
-		if entry.is_dir() {			
+		if entry.is_dir() && (run_parms.link_policy != LinkPolicy::Copy || !is_link(entry)) {			
 
-			path_string.clear();
-			path_string.push_str(&_target_base);
-			
-			let temp_string = match &entry.to_str() {
-				Some(temp_string) => temp_string,
-				None => "None value",
-			};
+			source_prefix = drive_id(entry);
 
 			for x in 0..drive_count {
 			
-				source_prefix.clear();
-				source_prefix.push_str(&temp_string[0..2].to_string());
-				
-				if &_drive_id[x] == &source_prefix {
+				if _drive_id[x] == source_prefix {
 					_drive_ct[x] += 1;
 				};
 				
 			};
 			
-			entry_length = temp_string.len();
-			path_string.push_str(&temp_string[0..1].to_string());
-			path_string.push_str(&temp_string[2..entry_length].to_string());
-			final_path.clear();
-			final_path.push(&path_string);
-			
-			if !final_path.is_dir() {
-				let _vbnm = match fs::create_dir_all(&final_path) {
-					Ok(_vbnm) => my_new_dir += 1,
-					Err(_vbnm) => info!("{:?} {:?}", &final_path, _vbnm)
-				};
-	
+			for target in targets.iter_mut().filter(|t| t.usable) {
 			
+				final_path = backup_path(target, entry, run_parms.case_mode, &mut case_renames, &mut run_report);
+				
+				if !final_path.is_dir() {
+					match fs::create_dir_all(&final_path) {
+						Ok(_vbnm) => {
+							my_new_dir += 1;
+							target.stats.dirs_created += 1;
+							debug!(event = "mkdir", source_path:% = entry.display(), target_path:% = final_path.display();
+								   "Created directory {:?}", &final_path);
+						},
+						Err(_vbnm) => {
+							target.stats.errors += 1;
+							run_report.record_failure("create_dir_all", &final_path, &_vbnm);
+						}
+					}
+				}
 			}
 		}
 	}
 
 	for x in 0..drive_count {
 		info!("Number of source directories on {:?} = {:?}", _drive_id[x], _drive_ct[x]);
+		run_report.drives.push((_drive_id[x].clone(), _drive_ct[x]));
 	}
 	info!("Number of target directories created = {:?}", my_new_dir);
+	run_report.dirs_created = my_new_dir;
+	
+}
+
+//	With LinkPolicy Copy, links are recreated in the target pointing at the
+//	same place as the source link. The directory they are in has been
+//	created by the block above.
+
+if run_parms.link_policy == LinkPolicy::Copy {
+
+	let mut links_copied: usize = 0;
+	
+	for entry in &_bkup_s1 {
+		
+		if !is_link(entry) {
+			continue;
+		}
+		
+		for target in targets.iter_mut().filter(|t| t.usable) {
+		
+			let final_path = build_target_path(&target.base, entry);
+			
+			if let Err(err) = ensure_parent(&final_path) {
+				target.stats.errors += 1;
+				run_report.record_failure("create_dir_all", &final_path, &err);
+				continue;
+			}
+			
+			match copy_link(entry, &final_path) {
+				Ok(true) => {
+					links_copied += 1;
+					debug!(event = "link", source_path:% = entry.display(), target_path:% = final_path.display();
+						   "Linked => {:?}", &final_path);
+				},
+				Ok(false) => (),
+				Err(err) => {
+					target.stats.errors += 1;
+					run_report.record_failure("copy_link", entry, &err);
+				},
+			}
+		}
+	}
 	
+	info!("Number of links copied = {}", links_copied);
+
 }
 
 //	The following block of code performs the actual copying of files
@@ -426,84 +910,440 @@ fn main() {
 
 	info!("File backup operation(s) initiated");
 	let start_now = Instant::now();
+	let run_stamp: String = chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
+	let mut _retry_queue = Vec::<(PathBuf, Vec<(usize, PathBuf)>, bool)>::new();
+	let mut throttle = Throttle::new(run_parms.throttle_rate, run_parms.throttle_window);
+	
+	for target in targets.iter_mut().filter(|t| t.usable) {
+		target.catalog = open_catalog(&target.base);
+	}
+	
+	if run_parms.throttle_rate > 0 {
+		info!("Copy rate limited to {}/s{}", format_bytes(run_parms.throttle_rate),
+			  match run_parms.throttle_window {
+				  Some((start, end)) => format!(" between {:02}:{:02} and {:02}:{:02}",
+												 start / 60, start % 60, end / 60, end % 60),
+				  None => String::new(),
+			  });
+	}
+
+//	First work out which files need to be copied, and where to. A file is
+//	copied to a backup location if it does not exist there, or if its size
+//	or last write time differ from the copy there. Building the plan up
+//	front lets us report progress against the total number of files and
+//	bytes to be copied.
 
+	let mut _copy_plan = Vec::<CopyItem>::new();
+	
 {
 
-	let mut entry_length: usize = 0;	
 	let mut final_path = PathBuf::new();	
-	let mut path_string = String::with_capacity(100);
 	
 	for entry in &_bkup_s1 {
 		
-		if entry.is_file() {
+		if entry.is_file() && (run_parms.link_policy == LinkPolicy::Follow || !is_link(entry)) {
+			
+			source_filesize = 0;
+			if let Err(err) = get_meta(&entry.to_path_buf(),
+		             &mut source_file_attrib,
+		             &mut source_creation_time,
+		             &mut source_access_time,
+		             &mut source_last_write_time,
+		             &mut source_filesize) {
+				run_report.record_failure("metadata", entry, &err);
+			}
+			
+			let mut copy_targets = Vec::<CopyTarget>::new();
 			
-			path_string.push_str(&_target_base);
+			for (index, target) in targets.iter_mut().enumerate() {
 			
-			let temp_string = match &entry.to_str() {
-				Some(temp_string) => temp_string,
-				None => "None value",
+				if !target.usable {
+					continue;
+				}
+				
+				final_path = backup_path(target, entry, run_parms.case_mode, &mut case_renames, &mut run_report);
+				
+				if !final_path.exists() {
+					target.plan_growth += source_filesize;
+					copy_targets.push(CopyTarget {
+						index,
+						path: final_path.clone(),
+						readonly: false,
+					});
+				}
+				else {
+					if let Err(err) = get_meta(&final_path,
+				             &mut target_file_attrib,
+				             &mut target_creation_time,
+				             &mut target_access_time,
+				             &mut target_last_write_time,
+				             &mut target_filesize) {
+						run_report.record_failure("metadata", &final_path, &err);
+					}
+					
+					if source_last_write_time != target_last_write_time ||
+						source_filesize != target_filesize {	
+						target.plan_growth += source_filesize;
+						copy_targets.push(CopyTarget {
+							index,
+							path: final_path.clone(),
+							readonly: target_file_attrib & FILE_ATTRIBUTE_READONLY ==
+								FILE_ATTRIBUTE_READONLY,
+						});
+					}
+				}
+			}
+			
+			if !copy_targets.is_empty() {
+				_copy_plan.push(CopyItem {
+					source: entry.to_path_buf(),
+					size: source_filesize,
+					targets: copy_targets,
+				});
+			}
+			
+		}
+		
+	}
+	
+	info!("Number of case-only renames in the backup = {}", case_renames);
+	info!("Number of files to copy = {}, {} bytes",
+		  _copy_plan.len(), _copy_plan.iter().map(|c| c.size).sum::<u64>());
+	
+	if targets.len() > 1 {
+		for (index, target) in targets.iter().enumerate().filter(|(_, t)| t.usable) {
+			info!("Number of files to copy to {} = {}", target.base,
+				  _copy_plan.iter().filter(|c| c.targets.iter().any(|t| t.index == index)).count());
+		}
+	}
+
+}
+
+//	When reconciling, find the orphans in each backup location before
+//	anything is copied or moved. These are the files in the backup whose
+//	source file no longer exists. Since an unmounted drive or a mistyped
+//	source path would make every backup file look orphaned, a location is
+//	left alone if the number of orphans in it is above the limits set in
+//	the parameter file, unless --force-delete was given. This is checked
+//	now so that a location in that state is not restructured by move
+//	detection either.
+
+if run_parms.reconcile {
+
+	let mut source_names = NameIndex::new(run_parms.case_mode);
+	let (unavailable, walk_roots) = reconcile_roots(&_bkup_roots, &_skipped_roots, run_parms.case_mode);
+	
+	for target in targets.iter_mut().filter(|t| t.usable) {
+	
+		let mut backup_files: usize = 0;
+		
+		for root in &walk_roots {
+			let (mut root_orphans, root_files) = find_orphans(&target.base, root, &unavailable,
+															  &mut source_names, &mut run_report);
+			info!("{:?}: {} backup files in {}, {} orphans", root, root_files, target.base, root_orphans.len());
+			target.orphans.append(&mut root_orphans);
+			backup_files += root_files;
+		}
+		
+		let orphan_count = target.orphans.len();
+		let delete_percent = if backup_files > 0 {
+			orphan_count as f64 * 100.0 / backup_files as f64
+		} else {
+			0.0
+		};
+		
+		let over_count = run_parms.max_delete_count > 0 && orphan_count > run_parms.max_delete_count;
+		let over_percent = run_parms.max_delete_percent > 0.0 && delete_percent > run_parms.max_delete_percent;
+		
+		if (over_count || over_percent) && !run_parms.force_delete {
+			error!("Reconciliation would remove {} of {} backup files ({:.1}%) from {}, above the limit of {} files or {:.1}%",
+				   orphan_count, backup_files, delete_percent, target.base,
+				   run_parms.max_delete_count, run_parms.max_delete_percent);
+			error!("Nothing has been moved or removed. Check the sources, or run with --force-delete to proceed");
+			run_report.reconcile_notes.push(format!(
+				"Reconciliation of {} aborted: {} of {} backup files ({:.1}%) would have been removed",
+				target.base, orphan_count, backup_files, delete_percent));
+			run_report.orphans_found += orphan_count;
+			target.orphans.clear();
+			target.reconcile_aborted = true;
+		}
+		else if over_count || over_percent {
+			warn!("Deletion limits exceeded for {}, continuing because --force-delete was given", target.base);
+		}
+	}
+}
+
+//	Look for files that have been moved rather than added. Any orphan that
+//	matches a new file in the plan is renamed to its target path in the
+//	backup, so a moved folder is not copied again and then removed from its
+//	old place. This is only done when reconciling, since otherwise the old
+//	copy would have been kept. Each backup location is looked at on its own,
+//	since a file may have been copied to one of them and not yet to another.
+
+if run_parms.reconcile && run_parms.detect_moves && !_copy_plan.is_empty() {
+
+	for (index, target) in targets.iter_mut().enumerate() {
+	
+		if !target.usable || target.reconcile_aborted {
+			continue;
+		}
+		
+		let moves = find_moves(&_copy_plan, index, &target.orphans, run_parms.move_min_size,
+							   run_parms.move_verify, run_parms.case_mode);
+		
+		for (x, orphan) in &moves {
+		
+			let item = &mut _copy_plan[*x];
+			
+			let pos = match item.targets.iter().position(|t| t.index == index) {
+				Some(pos) => pos,
+				None => continue,
 			};
 			
-			entry_length = temp_string.len();
-			path_string.push_str(&temp_string[0..1].to_string());
-			path_string.push_str(&temp_string[2..entry_length].to_string());
+			let final_path = item.targets[pos].path.clone();
+			
+			if !inside_backup(&target.base, orphan) {
+				continue;
+			}
+			
+			if let Err(err) = ensure_parent(&final_path) {
+				target.stats.errors += 1;
+				run_report.record_failure("create_dir_all", &final_path, &err);
+				continue;
+			}
+			
+			match fs::rename(orphan, &final_path) {
+				Ok(_) => {
+					info!(event = "move", source_path:% = item.source.display(), target_path:% = final_path.display(),
+						  old_path:% = orphan.display(), bytes = item.size;
+						  "Moved => {:?} {:?}", orphan, &final_path);
+					if let Some(cat_file) = target.catalog.as_mut() {
+						catalog_record(cat_file, &run_stamp, &item.source, &final_path, item.size);
+					}
+					run_report.files_moved += 1;
+					run_report.bytes_moved += item.size;
+					target.stats.files_moved += 1;
+					target.plan_growth = target.plan_growth.saturating_sub(item.size);
+					target.orphans.retain(|(path, _)| path != orphan);
+					item.targets.remove(pos);
+				},
+				Err(err) => {
+					target.stats.errors += 1;
+					run_report.record_failure("rename", orphan, &err);
+				},
+			}
+		}
+	}
+	
+	_copy_plan.retain(|item| !item.targets.is_empty());
+	
+	info!("Number of files moved in the backup = {}, {}",
+		  run_report.files_moved, format_bytes(run_report.bytes_moved));
+	
+}
+
+//	Make sure each target has room for the plan before we start, rather
+//	than finding out part way through when every copy starts failing. A
+//	file that replaces an existing copy needs its full size, since the new
+//	copy is written next to the old one before it takes its place. Targets
+//	on the same volume share its free space, so what they need is added
+//	together. A target without room is left out of the job if
+//	FreeSpaceAction is Abort.
+
+{
+
+	let volumes: Vec<String> = targets.iter()
+		.map(|t| volume_identity(Path::new(&t.base)).map(|(_, identity)| identity).unwrap_or_else(|| t.base.clone()))
+		.collect();
+	let volume_growth: Vec<u64> = volumes.iter()
+		.map(|volume| targets.iter().zip(volumes.iter())
+			 .filter(|(t, v)| t.usable && *v == volume)
+			 .map(|(t, _)| t.plan_growth)
+			 .sum())
+		.collect();
+	
+	for (index, target) in targets.iter_mut().enumerate().filter(|(_, t)| t.usable) {
+	
+		match available_space(Path::new(&target.base)) {
+			Ok(available) => {
+				let needed = volume_growth[index] + run_parms.free_space_margin;
+				info!("Space needed on {} = {}, available = {}",
+					  target.base, format_bytes(target.plan_growth), format_bytes(available));
+				if volume_growth[index] != target.plan_growth {
+					info!("{} shares its volume with other backup locations, {} needed on the volume in all",
+						  target.base, format_bytes(volume_growth[index]));
+				}
+				if needed > available {
+					if run_parms.free_space_abort {
+						error!("Not enough free space on {} - {} needed including a margin of {}, {} available",
+							   target.base, format_bytes(needed), format_bytes(run_parms.free_space_margin),
+							   format_bytes(available));
+						target.skip(RC12, "not enough free space");
+						continue;
+					}
+					warn!("{} may run out of space - {} needed including a margin of {}, {} available",
+						  target.base, format_bytes(needed), format_bytes(run_parms.free_space_margin),
+						  format_bytes(available));
+				}
+			},
+			Err(err) => {
+				warn!("Unable to get free space on {} {:?}", target.base, err);
+			}
+		}
+	}
+	
+	if !targets.iter().any(|t| t.usable) {
+		info!("Ending job {}", job.name);
+		return finish_job(job, log_file_name, run_report, &targets);
+	}
+
+}
+
+//	Now copy the files in the plan, retrying transient failures. Each file
+//	is read once and written to every backup location it is going to.
+//	Anything that still fails with a transient error is put on the deferred
+//	queue and tried again once everything else has been copied. A target
+//	that fills up is not copied to any more, and the others carry on. The
+//	run can be cancelled by creating the stop file in any of the target
+//	backup locations.
+
+	let mut cancel = CancelCheck::new(&job.targets,
+									  targets.iter().filter_map(|t| t.lock.as_ref()).map(|l| l.path().to_path_buf()).collect());
+	
+{
+
+	let mut progress = Progress::new(run_parms.progress,
+									 run_parms.progress_interval,
+									 _copy_plan.len(),
+									 _copy_plan.iter().map(|c| c.size).sum());
+	
+	for item in &_copy_plan {
+	
+		if cancel.cancelled() {
+			warn!("Run cancelled, remaining files not copied");
+			break;
+		}
+		
+		if !targets.iter().any(|t| t.copying) {
+			error!("No backup location has room, remaining files not copied");
+			break;
+		}
+		
+		let entry = &item.source;
+		progress.start_file(entry);
+		
+		let mut copy_paths = Vec::<PathBuf>::new();
+		let mut copy_indexes = Vec::<usize>::new();
+		
+		for copy_target in &item.targets {
+		
+			let target = &mut targets[copy_target.index];
+			let final_path = &copy_target.path;
+			
+			if !target.copying {
+				continue;
+			}
+			
+			target_flag = true;
+			
+			if copy_target.readonly {
+				if let Err(err) = make_file_writable(final_path, &mut target_flag) {
+					target.stats.errors += 1;
+					run_report.record_failure("set_permissions", final_path, &err);
+				}
+			}
+			
+			if target_flag {
+				if let Err(err) = ensure_parent(final_path) {
+					target.stats.errors += 1;
+					run_report.record_failure("create_dir_all", final_path, &err);
+					target_flag = false;
+				}
+			}
+			
+			if target_flag {
+				copy_paths.push(final_path.clone());
+				copy_indexes.push(copy_target.index);
+			}
+		}
+		
+		if !copy_paths.is_empty() {
+		
+			let copy_start = Instant::now();
+			let results = copy_with_retry(entry, &copy_paths, run_parms, &mut throttle,
+										  &mut |n| { progress.add_bytes(n); !cancel.cancelled() });
+			let mut copied: Option<u64> = None;
+			let mut deferred = Vec::<(usize, PathBuf)>::new();
+			
+			for ((index, final_path), result) in copy_indexes.iter().zip(copy_paths.iter()).zip(results) {
+				let defer = if run_parms.retry_deferred { Some(&mut deferred) } else { None };
+				if let Some(n) = copy_result(&mut targets[*index], *index, entry, final_path, result,
+											 copy_start, &run_stamp, defer, &mut run_report) {
+					copied = Some(n);
+				}
+			}
+			
+			if let Some(n) = copied {
+				bytes_copied_u64 += n;
+				files_copied_f64 += 1.0;
+				run_report.record_copy(entry, n);
+			}
+			
+			if !deferred.is_empty() {
+				_retry_queue.push((entry.to_path_buf(), deferred, copied.is_some()));
+			}
+		}
+		
+		progress.file_done(item.size);
+	}
+	
+	progress.finish();
+
+//	Work through the deferred queue. By now whatever had the files locked
+//	may have let go of them. Files that still fail are recorded as errors.
+//	A file is only counted once, even if it was copied to some locations
+//	the first time round and to the rest from here.
 
-			final_path.push(&path_string);
+	if !_retry_queue.is_empty() && !cancel.cancelled() {
+	
+		info!("Retrying {} deferred file(s)", _retry_queue.len());
+		let mut recovered: usize = 0;
+		
+		for (entry, deferred, counted) in &_retry_queue {
+		
+			let (copy_indexes, copy_paths): (Vec<usize>, Vec<PathBuf>) = deferred.iter()
+				.filter(|(index, _)| targets[*index].copying)
+				.cloned()
+				.unzip();
 			
-			if !final_path.exists() {
-				
-				match fs::copy(&entry, &final_path) {
-					Ok(n) => {
-						bytes_copied_u64 += n;
-						files_copied_f64 += 1.0;
-						info!("Copied => {:?} {:?}", &entry, n);
-					},
-					Err(err) => info!("fs::copy error {:?}", err),
-				};
+			if copy_paths.is_empty() {
+				continue;
 			}
-			else {
-				get_meta(&entry.to_path_buf(),
-			             &mut source_file_attrib,
-			             &mut source_creation_time,
-			             &mut source_access_time,
-			             &mut source_last_write_time,
-			             &mut source_filesize);
-						 
-				get_meta(&final_path,
-			             &mut target_file_attrib,
-			             &mut target_creation_time,
-			             &mut target_access_time,
-			             &mut target_last_write_time,
-			             &mut target_filesize);
+			
+			let copy_start = Instant::now();
+			let results = copy_with_retry(entry, &copy_paths, run_parms, &mut throttle,
+										  &mut |_| !cancel.cancelled());
+			let mut copied: Option<u64> = None;
+			
+			for ((index, final_path), result) in copy_indexes.iter().zip(copy_paths.iter()).zip(results) {
+				if let Some(n) = copy_result(&mut targets[*index], *index, entry, final_path, result,
+											 copy_start, &run_stamp, None, &mut run_report) {
+					copied = Some(n);
 				}
-			if source_last_write_time != target_last_write_time ||
-				source_filesize != target_filesize {	
-  
-				   	if target_file_attrib & FILE_ATTRIBUTE_READONLY ==
-						FILE_ATTRIBUTE_READONLY {
-							target_flag = true;
-							make_file_writable(&final_path, &mut target_flag);
-						}
-						
-					if !target_flag { println!("Target flag is false") };
-					
-					if target_flag {
-						match fs::copy(&entry, &final_path) {
-							Ok(n) => {
-								bytes_copied_u64 += n;
-								files_copied_f64 += 1.0;
-								info!("Copied => {:?} {:?}", &entry, n);
-							},
-							Err(err) => info!("{:?} {:?}", &entry, err),					   
-						};
-					}			   
-			   
 			}
 			
+			if let Some(n) = copied {
+				recovered += 1;
+				if !counted {
+					bytes_copied_u64 += n;
+					files_copied_f64 += 1.0;
+					run_report.record_copy(entry, n);
+				}
+			}
 		}
 		
-		final_path.clear();
-		path_string.clear();
+		info!("Deferred files copied = {}, still failing = {}",
+			  recovered, _retry_queue.len() - recovered);
 	}
 
 }
@@ -511,7 +1351,7 @@ fn main() {
 	info!("File backup operation(s) complete!");
 	info!("Total files copied = {:.0}", files_copied_f64);
 	info!("Time to perform backups = {:.2} seconds.", start_now.elapsed().as_secs_f64());
-	info!("Average duration per backup = {:.2} seconds.", start_now.elapsed().as_secs_f64() / files_copied_f64s);
+	info!("Average duration per backup = {:.2} seconds.", start_now.elapsed().as_secs_f64() / files_copied_f64);
 	
 	if files_copied_f64 > 0.0 {
 	
@@ -543,6 +1383,241 @@ fn main() {
 	
 	}
 	
-	info!("Terminating program execution");
+//	Reconciliation. Remove the orphans found before the copy from each
+//	backup location, less any that were moved into place since. Locations
+//	that were over the deletion limits are left alone, and the backups of
+//	source roots that are not available are not touched. A cancelled run
+//	does not reconcile.
+
+if run_parms.reconcile && !cancel.cancelled() {
 
-}
\ No newline at end of file
+	info!("Reconciliation initiated");
+	
+	let mut source_names = NameIndex::new(run_parms.case_mode);
+	
+	let (unavailable, walk_roots) = reconcile_roots(&_bkup_roots, &_skipped_roots, run_parms.case_mode);
+	
+	for root in &unavailable {
+		error!("Source {:?} is not available, its backup will not be reconciled", root);
+		run_report.reconcile_notes.push(format!("{} is not available, not reconciled", root.display()));
+	}
+	
+	for target in targets.iter_mut().filter(|t| t.usable && !t.reconcile_aborted) {
+	
+		let mut dirs_removed: usize = 0;
+		let orphans = std::mem::take(&mut target.orphans);
+		
+		run_report.orphans_found += orphans.len();
+		
+		for (orphan, size) in &orphans {
+		
+			if !inside_backup(&target.base, orphan) {
+				error!("{:?} is reached through a link out of the backup, not removed", orphan);
+				run_report.reconcile_notes.push(format!("{} is outside the backup, not removed", orphan.display()));
+				continue;
+			}
+			
+			target_flag = true;
+			if let Ok(meta) = fs::symlink_metadata(orphan) {
+				if meta.permissions().readonly() && !meta.file_type().is_symlink() {
+					if let Err(err) = make_file_writable(orphan, &mut target_flag) {
+						target.stats.errors += 1;
+						run_report.record_failure("set_permissions", orphan, &err);
+						continue;
+					}
+				}
+			}
+			
+			match remove_backup_entry(orphan) {
+				Ok(_) => {
+					info!(event = "delete", target_path:% = orphan.display(), bytes = *size;
+						  "Removed => {:?} {:?}", orphan, size);
+					run_report.orphans_removed.push((orphan.display().to_string(), *size));
+					target.stats.orphans_removed += 1;
+				},
+				Err(err) => {
+					target.stats.errors += 1;
+					run_report.record_failure("remove_file", orphan, &err);
+				},
+			}
+		}
+		
+//	With the orphaned files gone, remove the directories they were in from
+//	the bottom up. Only empty directories are removed, so one that still
+//	holds something, such as a file that could not be deleted, is left.
+
+		for root in &walk_roots {
+		
+			for dir in find_orphan_dirs(&target.base, root, &unavailable, run_parms.keep_empty_dirs,
+											  &mut source_names, &mut run_report) {
+			
+				let empty = fs::read_dir(&dir).map(|mut d| d.next().is_none()).unwrap_or(false);
+				
+				if !empty || !inside_backup(&target.base, &dir) {
+					continue;
+				}
+				
+				match fs::remove_dir(&dir) {
+					Ok(_) => {
+						debug!(event = "rmdir", target_path:% = dir.display();
+							   "Removed directory {:?}", &dir);
+						run_report.dirs_removed += 1;
+						dirs_removed += 1;
+					},
+					Err(err) => {
+						target.stats.errors += 1;
+						run_report.record_failure("remove_dir", &dir, &err);
+					},
+				}
+			}
+		}
+		
+		if job.targets.len() > 1 {
+			info!("Reconciliation of {} complete, {} orphans found, {} removed, {} directories removed",
+				  target.base, orphans.len(), target.stats.orphans_removed, dirs_removed);
+		}
+	}
+	
+	info!("Reconciliation complete, {} orphans found, {} removed, {} directories removed",
+		  run_report.orphans_found, run_report.orphans_removed.len(), run_report.dirs_removed);
+
+}
+
+	run_report.elapsed_secs = start_now.elapsed().as_secs_f64();
+	run_report.files_copied = files_copied_f64 as u64;
+	run_report.bytes_copied = bytes_copied_u64;
+	run_report.mean_file_size = mean_file_size_f64;
+	
+	finish_job(job, log_file_name, run_report, &targets)
+}
+
+//	Finish the run report for a job and write it out. This is done however
+//	the job ends once its backup locations are known, so that a run where
+//	every location was skipped still leaves a report and a summary of what
+//	went wrong. Returns the return code for the job along with its report.
+//
+//	Function parameters:
+//
+//	job - the job that was run
+//	log_file_name - name of the log file, used to name the reports
+//	run_report - the run report for the job
+//	targets - the backup locations for the job
+
+fn finish_job(job: &JobConfig,
+			  log_file_name: &Path,
+			  mut run_report: RunReport,
+			  targets: &[BackupTarget]) -> (i32, RunReport) {
+
+	run_report.target_base = job.targets.join(", ");
+	run_report.targets = targets.iter().map(|t| t.stats.clone()).collect();
+	run_report.run_end = chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
+	
+	run_report.log_failure_summary();
+	
+	let report_name = job_report_name(log_file_name, &job.name);
+	write_json_report(&report_name, &run_report);
+	write_html_report(&report_name, &run_report);
+	
+	(targets.iter().map(|t| t.rc).max().unwrap_or(RC00), run_report)
+}
+
+//	Work out where a source file or directory goes in a backup location.
+//	When case does not matter, a backup name that only differs in case from
+//	the source is renamed to match first.
+//
+//	Function parameters:
+//
+//	target - the backup location
+//	source - the source file or directory
+//	case_mode - whether names that differ only in case are the same
+//	case_renames - count of case-only renames, added to for a rename
+//	run_report - the run report, for a rename that fails
+
+fn backup_path(target: &mut BackupTarget,
+			   source: &Path,
+			   case_mode: CaseMode,
+			   case_renames: &mut usize,
+			   run_report: &mut RunReport) -> PathBuf {
+
+	let final_path = build_target_path(&target.base, source);
+	
+	if case_mode.insensitive() {
+		match fix_name_case(&final_path, &mut target.names) {
+			Ok(true) => {
+				*case_renames += 1;
+				info!(event = "rename", target_path:% = final_path.display();
+					  "Renamed to match source case => {:?}", &final_path);
+			},
+			Ok(false) => (),
+			Err(err) => run_report.record_failure("rename", &final_path, &err),
+		}
+	}
+	
+	final_path
+}
+
+//	Account for the result of copying a file to one backup location. A copy
+//	that worked is logged, added to the catalog and counted against the
+//	location. A location that is full is not copied to any more. Other
+//	failures are errors, unless they are transient and a deferred list is
+//	given, in which case the copy is put on it to be tried again later.
+//	Returns the number of bytes copied if the copy worked.
+//
+//	Function parameters:
+//
+//	target - the backup location
+//	index - position of the backup location in the job
+//	source - the file that was copied
+//	final_path - where it was copied to
+//	result - the result of the copy
+//	copy_start - when the copy started, for its duration
+//	run_stamp - time stamp of the run, for the catalog
+//	deferred - the deferred list, or None if failures are not deferred
+//	run_report - the run report
+
+#[allow(clippy::too_many_arguments)]
+fn copy_result(target: &mut BackupTarget,
+			   index: usize,
+			   source: &Path,
+			   final_path: &Path,
+			   result: io::Result<u64>,
+			   copy_start: Instant,
+			   run_stamp: &str,
+			   deferred: Option<&mut Vec<(usize, PathBuf)>>,
+			   run_report: &mut RunReport) -> Option<u64> {
+
+	let err = match result {
+		Ok(n) => {
+			target.stats.files_copied += 1;
+			target.stats.bytes_copied += n;
+			info!(event = "copy", source_path:% = source.display(), target_path:% = final_path.display(),
+				  bytes = n, duration_ms = copy_start.elapsed().as_millis() as u64;
+				  "Copied => {:?} {:?}", source, n);
+			if let Some(cat_file) = target.catalog.as_mut() {
+				catalog_record(cat_file, run_stamp, source, final_path, n);
+			}
+			return Some(n);
+		},
+		Err(err) => err,
+	};
+	
+	let kind = classify_io_error(&err);
+	
+	if kind == FailureKind::DiskFull {
+		target.stats.errors += 1;
+		run_report.record_failure("fs::copy", source, &err);
+		error!("{} is full, no more files will be copied to it", target.base);
+		target.copying = false;
+	}
+	else if let Some(deferred) = deferred.filter(|_| is_transient(kind)) {
+		warn!(event = "retry", source_path:% = source.display(), target_path:% = final_path.display();
+			  "Deferring {:?} {:?}", source, err);
+		deferred.push((index, final_path.to_path_buf()));
+	}
+	else {
+		target.stats.errors += 1;
+		run_report.record_failure("fs::copy", source, &err);
+	}
+	
+	None
+}